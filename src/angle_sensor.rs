@@ -0,0 +1,350 @@
+//! Magnetic angle sensors (AS5600, AS5048A) for hall effect gimbals
+//!
+//! The readings are meant to be fed into a [`LinearInput`](crate::inputs::LinearInput), just like
+//! the readings of the ADC.
+use embedded_hal::blocking::{i2c::WriteRead, spi::Transfer};
+use embedded_hal::digital::v2::OutputPin;
+
+/// Strength of the magnetic field as seen by the sensor
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MagnetStatus {
+    Ok,
+    NotDetected,
+    TooWeak,
+    TooStrong,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    /// Error on the underlying bus
+    Bus(E),
+    /// Corrupted frame or error flag set by the sensor
+    Frame,
+    /// The magnet is not usable, the angle can not be trusted
+    Magnet(MagnetStatus),
+}
+
+/// A sensor providing an absolute angle, sitting on a bus of type `BUS`
+pub trait AngleSensor<BUS> {
+    type Error;
+
+    /// Resolution of the angle in bits
+    const BITS: u32;
+
+    /// Read the raw angle
+    fn angle(&mut self, bus: &mut BUS) -> Result<u16, Error<Self::Error>>;
+
+    /// Read the status of the magnet
+    fn magnet(&mut self, bus: &mut BUS) -> Result<MagnetStatus, Error<Self::Error>>;
+}
+
+/// AS5600, 12 bit via I2C
+///
+/// The I2C address of the AS5600 is fixed, so there can only be one sensor per bus.
+pub struct As5600;
+
+impl As5600 {
+    const ADDRESS: u8 = 0x36;
+    const REG_STATUS: u8 = 0x0b;
+    const REG_RAW_ANGLE: u8 = 0x0c;
+
+    const STATUS_MH: u8 = 1 << 3;
+    const STATUS_ML: u8 = 1 << 4;
+    const STATUS_MD: u8 = 1 << 5;
+}
+
+impl<I2C: WriteRead> AngleSensor<I2C> for As5600 {
+    type Error = I2C::Error;
+
+    const BITS: u32 = 12;
+
+    fn angle(&mut self, i2c: &mut I2C) -> Result<u16, Error<Self::Error>> {
+        let mut buf = [0u8; 2];
+        i2c.write_read(Self::ADDRESS, &[Self::REG_RAW_ANGLE], &mut buf)
+            .map_err(Error::Bus)?;
+        Ok(u16::from_be_bytes(buf) & 0x0fff)
+    }
+
+    fn magnet(&mut self, i2c: &mut I2C) -> Result<MagnetStatus, Error<Self::Error>> {
+        let mut buf = [0u8; 1];
+        i2c.write_read(Self::ADDRESS, &[Self::REG_STATUS], &mut buf)
+            .map_err(Error::Bus)?;
+        let status = buf[0];
+
+        Ok(if status & Self::STATUS_MD == 0 {
+            MagnetStatus::NotDetected
+        } else if status & Self::STATUS_ML != 0 {
+            MagnetStatus::TooWeak
+        } else if status & Self::STATUS_MH != 0 {
+            MagnetStatus::TooStrong
+        } else {
+            MagnetStatus::Ok
+        })
+    }
+}
+
+/// AS5048A, 14 bit via SPI (mode 1)
+///
+/// Multiple sensors can share one bus, each with its own chip select pin.
+pub struct As5048<CS> {
+    cs: CS,
+}
+
+impl<CS: OutputPin> As5048<CS> {
+    const CMD_READ: u16 = 1 << 14;
+    const REG_NOP: u16 = 0x0000;
+    const REG_CLEAR_ERROR: u16 = 0x0001;
+    const REG_DIAGNOSTICS: u16 = 0x3ffd;
+    const REG_ANGLE: u16 = 0x3fff;
+
+    const FLAG_ERROR: u16 = 1 << 14;
+    const DIAG_OCF: u16 = 1 << 8;
+    const DIAG_COF: u16 = 1 << 9;
+    const DIAG_COMP_LOW: u16 = 1 << 10;
+    const DIAG_COMP_HIGH: u16 = 1 << 11;
+
+    pub fn new(mut cs: CS) -> Self {
+        cs.set_high().ok();
+        Self { cs }
+    }
+
+    /// Add the even parity bit to a command
+    fn with_parity(cmd: u16) -> u16 {
+        if (cmd & 0x7fff).count_ones() % 2 == 1 {
+            cmd | 0x8000
+        } else {
+            cmd
+        }
+    }
+
+    /// Send one frame, returns the response to the previous frame
+    fn frame<SPI: Transfer<u8>>(
+        &mut self,
+        spi: &mut SPI,
+        cmd: u16,
+    ) -> Result<u16, Error<SPI::Error>> {
+        let mut buf = Self::with_parity(cmd).to_be_bytes();
+        self.cs.set_low().ok();
+        let result = spi.transfer(&mut buf).map(|_| ());
+        self.cs.set_high().ok();
+        result.map_err(Error::Bus)?;
+
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Read a register
+    ///
+    /// The response to a command is only clocked out with the next frame, hence the trailing NOP.
+    fn read<SPI: Transfer<u8>>(
+        &mut self,
+        spi: &mut SPI,
+        register: u16,
+    ) -> Result<u16, Error<SPI::Error>> {
+        self.frame(spi, Self::CMD_READ | register)?;
+        let response = self.frame(spi, Self::CMD_READ | Self::REG_NOP)?;
+
+        if response.count_ones() % 2 == 1 {
+            return Err(Error::Frame);
+        }
+
+        if response & Self::FLAG_ERROR != 0 {
+            // reading the error register clears the flag
+            self.frame(spi, Self::CMD_READ | Self::REG_CLEAR_ERROR)?;
+            return Err(Error::Frame);
+        }
+
+        Ok(response & 0x3fff)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> AngleSensor<SPI> for As5048<CS> {
+    type Error = SPI::Error;
+
+    const BITS: u32 = 14;
+
+    fn angle(&mut self, spi: &mut SPI) -> Result<u16, Error<Self::Error>> {
+        self.read(spi, Self::REG_ANGLE)
+    }
+
+    fn magnet(&mut self, spi: &mut SPI) -> Result<MagnetStatus, Error<Self::Error>> {
+        let diag = self.read(spi, Self::REG_DIAGNOSTICS)?;
+
+        Ok(
+            if diag & Self::DIAG_OCF == 0 || diag & Self::DIAG_COF != 0 {
+                MagnetStatus::NotDetected
+            } else if diag & Self::DIAG_COMP_HIGH != 0 {
+                MagnetStatus::TooWeak
+            } else if diag & Self::DIAG_COMP_LOW != 0 {
+                MagnetStatus::TooStrong
+            } else {
+                MagnetStatus::Ok
+            },
+        )
+    }
+}
+
+/// Angle sensor with wrap-around compensation
+///
+/// The zero of the magnet can be anywhere within the stick travel. Therefore the angle of the
+/// stick center is shifted to half of the sensor's range, so the stick never crosses the
+/// discontinuity between the highest angle and zero. Without a known center the first valid
+/// reading is taken. Every reading is relative to the center, so it has to be stored along with
+/// the calibration and passed again after a restart, when the stick may rest anywhere.
+pub struct AngleInput<S> {
+    sensor: S,
+    center: Option<u16>,
+}
+
+impl<S> AngleInput<S> {
+    /// Sensor with the center stored with the calibration, `None` if there is none yet
+    pub fn new(sensor: S, center: Option<u16>) -> Self {
+        Self { sensor, center }
+    }
+
+    /// Raw angle taken as the stick center, `None` until the first valid reading
+    pub fn center(&self) -> Option<u16> {
+        self.center
+    }
+
+    /// Replace the center, `None` takes the next valid reading
+    ///
+    /// This shifts all readings, so any calibration based on them has to be redone.
+    pub fn set_center(&mut self, center: Option<u16>) {
+        self.center = center;
+    }
+
+    /// Read the wrap-around compensated angle
+    ///
+    /// Fails if the magnet is not in a usable state.
+    pub fn read<BUS>(&mut self, bus: &mut BUS) -> Result<u16, Error<S::Error>>
+    where
+        S: AngleSensor<BUS>,
+    {
+        match self.sensor.magnet(bus)? {
+            MagnetStatus::Ok => {}
+            status => return Err(Error::Magnet(status)),
+        }
+
+        let raw = self.sensor.angle(bus)?;
+        let full_scale = 1u16 << S::BITS;
+        let center = *self.center.get_or_insert(raw);
+
+        Ok(raw.wrapping_add(full_scale / 2).wrapping_sub(center) & (full_scale - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inputs::LinearInput;
+
+    /// Sensor whose magnet is at `angle`
+    struct Magnet {
+        angle: u16,
+    }
+
+    impl AngleSensor<()> for Magnet {
+        type Error = ();
+
+        const BITS: u32 = 12;
+
+        fn angle(&mut self, _: &mut ()) -> Result<u16, Error<()>> {
+            Ok(self.angle)
+        }
+
+        fn magnet(&mut self, _: &mut ()) -> Result<MagnetStatus, Error<()>> {
+            Ok(MagnetStatus::Ok)
+        }
+    }
+
+    #[test]
+    fn wrap_around() {
+        // the zero of the magnet lies within the stick travel
+        let mut input = AngleInput::new(Magnet { angle: 4000 }, None);
+        assert_eq!(input.read(&mut ()).unwrap(), 2048);
+        assert_eq!(input.center(), Some(4000));
+        input.sensor.angle = 100;
+        assert_eq!(input.read(&mut ()).unwrap(), 2048 + 196);
+        input.sensor.angle = 3000;
+        assert_eq!(input.read(&mut ()).unwrap(), 2048 - 1000);
+        // garbage centers do not overflow
+        input.set_center(Some(u16::MAX));
+        input.read(&mut ()).unwrap();
+    }
+
+    #[test]
+    fn boot_with_the_stick_off_center() {
+        // first boot, calibrated with the stick centered at 4000 and moved by 1000 to each side
+        let mut input = AngleInput::new(Magnet { angle: 4000 }, None);
+        let mut calibration = LinearInput::default();
+        for angle in [4000, 3000, 5000 % 4096, 4000].iter() {
+            input.sensor.angle = *angle;
+            calibration.get(input.read(&mut ()).unwrap());
+        }
+        calibration.set_center(input.read(&mut ()).unwrap());
+        let center = input.center();
+
+        // the next boot with the throttle stick resting at the bottom
+        let mut input = AngleInput::new(Magnet { angle: 3000 }, center);
+        let bottom = calibration.get(input.read(&mut ()).unwrap());
+        assert!(bottom < LinearInput::CENTER);
+        input.sensor.angle = 4000;
+        assert_eq!(
+            calibration.get(input.read(&mut ()).unwrap()),
+            LinearInput::CENTER
+        );
+        input.sensor.angle = 5000 % 4096;
+        assert_eq!(
+            calibration.get(input.read(&mut ()).unwrap()),
+            2 * LinearInput::CENTER - bottom
+        );
+
+        // without the stored center the bottom would read as centered
+        let mut input = AngleInput::new(Magnet { angle: 3000 }, None);
+        assert_eq!(
+            calibration.get(input.read(&mut ()).unwrap()),
+            LinearInput::CENTER
+        );
+    }
+
+    /// AS5600 registers, as seen through the I2C bus
+    struct Bus {
+        registers: [u8; 0x10],
+    }
+
+    impl WriteRead for Bus {
+        type Error = ();
+
+        fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), ()> {
+            assert_eq!(address, 0x36);
+            let start = bytes[0] as usize;
+            buffer.copy_from_slice(&self.registers[start..start + buffer.len()]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn as5600() {
+        let mut bus = Bus {
+            registers: [0; 0x10],
+        };
+        bus.registers[0x0b] = 1 << 5;
+        bus.registers[0x0c..0x0e].copy_from_slice(&0xf123u16.to_be_bytes());
+        let mut input = AngleInput::new(As5600, Some(0x123));
+        assert_eq!(input.read(&mut bus).unwrap(), 2048);
+
+        for (status, magnet) in [
+            (0, MagnetStatus::NotDetected),
+            (1 << 5 | 1 << 4, MagnetStatus::TooWeak),
+            (1 << 5 | 1 << 3, MagnetStatus::TooStrong),
+        ]
+        .iter()
+        {
+            bus.registers[0x0b] = *status;
+            match input.read(&mut bus) {
+                Err(Error::Magnet(m)) => assert_eq!(m, *magnet),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Acoustic feedback with an active buzzer
+use embedded_hal::digital::v2::OutputPin;
+use heapless::Deque;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Beep {
+    /// Short tone, e.g. a trim passing its center
+    Short,
+    /// Long tone, e.g. a trim hitting its limit
+    Long,
+}
+
+impl Beep {
+    /// Duration of the tone and of the pause after it in milliseconds
+    fn durations(self) -> (u32, u32) {
+        match self {
+            Self::Short => (40, 60),
+            Self::Long => (300, 100),
+        }
+    }
+}
+
+pub struct Buzzer<P> {
+    pin: P,
+    queue: Deque<Beep, 8>,
+    /// Current tone or pause as `(on, until)`
+    current: Option<(bool, u32)>,
+}
+
+impl<P: OutputPin> Buzzer<P> {
+    pub fn new(mut pin: P) -> Self {
+        pin.set_low().ok();
+        Self {
+            pin,
+            queue: Deque::new(),
+            current: None,
+        }
+    }
+
+    /// Queue a beep, dropped if too many are pending
+    pub fn beep(&mut self, beep: Beep) {
+        self.queue.push_back(beep).ok();
+    }
+
+    /// Advance the queue, `now` is in milliseconds
+    pub fn update(&mut self, now: u32) {
+        match self.current {
+            // still busy
+            Some((_, until)) if (now.wrapping_sub(until) as i32) < 0 => return,
+            // tone is over, pause
+            Some((true, _)) => {
+                let (_, pause) = self.queue.pop_front().unwrap().durations();
+                self.pin.set_low().ok();
+                self.current = Some((false, now.wrapping_add(pause)));
+                return;
+            }
+            _ => self.current = None,
+        }
+
+        if let Some(beep) = self.queue.front() {
+            let (tone, _) = beep.durations();
+            self.pin.set_high().ok();
+            self.current = Some((true, now.wrapping_add(tone)));
+        }
+    }
+}
@@ -0,0 +1,953 @@
+//! Compact binary encoding of the settings
+//!
+//! Integers are little endian, enums are a tag byte followed by their fields, sequences are
+//! prefixed with their length as one byte.
+use heapless::{String, Vec};
+
+use crate::buzzer::Beep;
+use crate::config::{HID_BUTTONS, VERSION};
+use crate::curves::{Curve, PointCurve, MAX_CURVES, MAX_POINTS};
+use crate::failsafe::FailsafeMode;
+use crate::flight_modes::FlightMode;
+use crate::globals::{Param, GLOBAL_LIMIT};
+use crate::heli::{Heli, Swash};
+use crate::hid_map::{Direction, HidMapping, Target};
+use crate::limits::Limit;
+use crate::logical_switches::{Function, LogicalSwitch};
+use crate::mixer::{Condition, MixLine, MixMode, Mixer, Source, Switch, CHANNELS, MAX_MIXES};
+use crate::report::Layout;
+use crate::slow::Times;
+use crate::special_functions::{Action, SpecialFunction};
+use crate::sticks::{ChannelOrder, ChannelOrders};
+use crate::throttle::ThrottleConfig;
+use crate::timers::{TimerConfig, Trigger, TIMERS};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The data ended too early
+    Truncated,
+    /// Unknown tag or value out of range
+    Invalid,
+    /// No more space to write to
+    Full,
+}
+
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    full: bool,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            full: false,
+        }
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        match self.buf.get_mut(self.len..self.len + data.len()) {
+            Some(dest) => {
+                dest.copy_from_slice(data);
+                self.len += data.len();
+            }
+            None => self.full = true,
+        }
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.bytes(&[v]);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn i16(&mut self, v: i16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn str(&mut self, v: &str) {
+        self.u8(v.len() as u8);
+        self.bytes(v.as_bytes());
+    }
+
+    /// Length of the encoded data, if everything fit
+    pub fn finish(self) -> Result<usize, Error> {
+        if self.full {
+            Err(Error::Full)
+        } else {
+            Ok(self.len)
+        }
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.data.len() {
+            return Err(Error::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn i16(&mut self) -> Result<i16, Error> {
+        Ok(self.u16()? as i16)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    pub fn str<const N: usize>(&mut self) -> Result<String<N>, Error> {
+        let len = self.u8()? as usize;
+        let s = core::str::from_utf8(self.bytes(len)?).map_err(|_| Error::Invalid)?;
+        let mut string = String::new();
+        string.push_str(s).map_err(|_| Error::Invalid)?;
+        Ok(string)
+    }
+
+    /// Index below `max`
+    pub fn index(&mut self, max: usize) -> Result<u8, Error> {
+        match self.u8()? {
+            i if (i as usize) < max => Ok(i),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    /// Length of a sequence of at most `max` elements
+    pub fn count(&mut self, max: usize) -> Result<usize, Error> {
+        self.index(max + 1).map(usize::from)
+    }
+
+    /// Whether everything was read
+    pub fn is_done(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+pub trait Encode {
+    fn encode(&self, w: &mut Writer);
+}
+
+pub trait Decode: Sized {
+    fn decode(r: &mut Reader) -> Result<Self, Error>;
+}
+
+impl Encode for Source {
+    fn encode(&self, w: &mut Writer) {
+        let (tag, i) = match *self {
+            Self::Axis(i) => (0, i),
+            Self::Trim(i) => (1, i),
+            Self::Input(i) => (2, i),
+            Self::Max => (3, 0),
+            Self::Channel(i) => (4, i),
+            Self::Heli(i) => (5, i),
+        };
+        w.u8(tag);
+        w.u8(i);
+    }
+}
+
+impl Decode for Source {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        let (tag, i) = (r.u8()?, r.u8()?);
+        Ok(match tag {
+            0 => Self::Axis(i),
+            1 => Self::Trim(i),
+            2 => Self::Input(i),
+            3 => Self::Max,
+            4 => Self::Channel(i),
+            5 => Self::Heli(i),
+            _ => return Err(Error::Invalid),
+        })
+    }
+}
+
+impl Encode for Switch {
+    fn encode(&self, w: &mut Writer) {
+        let (tag, i) = match *self {
+            Self::Input(i) => (0, i),
+            Self::Logical(i) => (1, i),
+            Self::FlightMode(i) => (2, i),
+            Self::Gesture(i) => (3, i),
+        };
+        w.u8(tag);
+        w.u8(i);
+    }
+}
+
+impl Decode for Switch {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        let (tag, i) = (r.u8()?, r.u8()?);
+        Ok(match tag {
+            0 => Self::Input(i),
+            1 => Self::Logical(i),
+            2 => Self::FlightMode(i),
+            3 => Self::Gesture(i),
+            _ => return Err(Error::Invalid),
+        })
+    }
+}
+
+impl Encode for Condition {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Self::Always => w.u8(0),
+            Self::On(switch) => {
+                w.u8(1);
+                switch.encode(w);
+            }
+            Self::Off(switch) => {
+                w.u8(2);
+                switch.encode(w);
+            }
+        }
+    }
+}
+
+impl Decode for Condition {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => Self::Always,
+            1 => Self::On(Switch::decode(r)?),
+            2 => Self::Off(Switch::decode(r)?),
+            _ => return Err(Error::Invalid),
+        })
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, w: &mut Writer) {
+        w.bool(self.is_some());
+        if let Some(v) = self {
+            v.encode(w);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(if r.bool()? { Some(T::decode(r)?) } else { None })
+    }
+}
+
+/// Same encoding as a plain `i16`
+impl Encode for Param {
+    fn encode(&self, w: &mut Writer) {
+        w.i16(self.to_raw());
+    }
+}
+
+impl Decode for Param {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Param::from_raw(r.i16()?).ok_or(Error::Invalid)
+    }
+}
+
+impl Encode for Curve {
+    fn encode(&self, w: &mut Writer) {
+        match *self {
+            Self::None => w.u8(0),
+            Self::Expo(k) => {
+                w.u8(1);
+                k.encode(w);
+            }
+            Self::Diff(d) => {
+                w.u8(2);
+                d.encode(w);
+            }
+            Self::Abs => w.u8(3),
+            Self::Custom(i) => {
+                w.u8(4);
+                w.u8(i);
+            }
+        }
+    }
+}
+
+impl Decode for Curve {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => Self::None,
+            1 => Self::Expo(Param::decode(r)?),
+            2 => Self::Diff(Param::decode(r)?),
+            3 => Self::Abs,
+            4 => Self::Custom(r.index(MAX_CURVES)?),
+            _ => return Err(Error::Invalid),
+        })
+    }
+}
+
+impl Encode for MixLine {
+    fn encode(&self, w: &mut Writer) {
+        w.u8(self.channel);
+        self.source.encode(w);
+        self.weight.encode(w);
+        self.offset.encode(w);
+        self.curve.encode(w);
+        self.condition.encode(w);
+        w.u8(match self.mode {
+            MixMode::Add => 0,
+            MixMode::Multiply => 1,
+            MixMode::Replace => 2,
+        });
+        w.u16(self.flight_modes);
+        self.delay.encode(w);
+        self.slow.encode(w);
+    }
+}
+
+impl Decode for MixLine {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Self::decode_version(r, VERSION)
+    }
+}
+
+impl MixLine {
+    /// Decode a mix line of a settings record of `version`
+    pub fn decode_version(r: &mut Reader, version: u8) -> Result<Self, Error> {
+        let channel = r.u8()?;
+        let mut mix = MixLine::new(channel, Source::decode(r)?);
+        mix.weight = Param::decode(r)?;
+        mix.offset = Param::decode(r)?;
+        mix.curve = Curve::decode(r)?;
+        mix.condition = Condition::decode(r)?;
+        mix.mode = match r.u8()? {
+            0 => MixMode::Add,
+            1 => MixMode::Multiply,
+            2 => MixMode::Replace,
+            _ => return Err(Error::Invalid),
+        };
+        mix.flight_modes = r.u16()?;
+        if version >= 8 {
+            mix.delay = Times::decode(r)?;
+            mix.slow = Times::decode(r)?;
+        }
+        Ok(mix)
+    }
+}
+
+impl Encode for Mixer {
+    fn encode(&self, w: &mut Writer) {
+        w.u8(self.mixes().len() as u8);
+        for mix in self.mixes() {
+            mix.encode(w);
+        }
+        w.u8(self.curves().len() as u8);
+        for curve in self.curves() {
+            w.u8(curve.points.len() as u8);
+            for point in curve.points.iter() {
+                point.encode(w);
+            }
+        }
+    }
+}
+
+impl Decode for Mixer {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Self::decode_version(r, VERSION)
+    }
+}
+
+impl Mixer {
+    /// Decode a mixer of a settings record of `version`
+    pub fn decode_version(r: &mut Reader, version: u8) -> Result<Self, Error> {
+        let mut mixer = Mixer::default();
+        for _ in 0..r.count(MAX_MIXES)? {
+            let mix = MixLine::decode_version(r, version)?;
+            mixer.add(mix).map_err(|_| Error::Invalid)?;
+        }
+        for i in 0..r.count(MAX_CURVES)? {
+            let mut points = Vec::new();
+            for _ in 0..r.count(MAX_POINTS)? {
+                let point = match Param::decode(r)? {
+                    Param::Value(v) => Param::Value(num::clamp(v, -GLOBAL_LIMIT, GLOBAL_LIMIT)),
+                    point => point,
+                };
+                points.push(point).ok();
+            }
+            mixer
+                .set_curve(i, PointCurve { points })
+                .map_err(|_| Error::Invalid)?;
+        }
+        Ok(mixer)
+    }
+}
+
+impl Encode for Times {
+    fn encode(&self, w: &mut Writer) {
+        w.u16(self.up);
+        w.u16(self.down);
+    }
+}
+
+impl Decode for Times {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            up: r.u16()?,
+            down: r.u16()?,
+        })
+    }
+}
+
+impl Encode for Heli {
+    fn encode(&self, w: &mut Writer) {
+        w.u8(match self.swash {
+            None => 0,
+            Some(Swash::H1) => 1,
+            Some(Swash::H120) => 2,
+            Some(Swash::H140) => 3,
+            Some(Swash::H90) => 4,
+        });
+        self.aileron.encode(w);
+        self.elevator.encode(w);
+        self.collective.encode(w);
+        self.throttle.encode(w);
+        w.i16(self.aileron_weight);
+        w.i16(self.elevator_weight);
+        w.i16(self.collective_weight);
+        w.u8(self.ring);
+        self.pitch_curves.encode(w);
+        self.throttle_curves.encode(w);
+    }
+}
+
+impl Decode for Heli {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            swash: match r.u8()? {
+                0 => None,
+                1 => Some(Swash::H1),
+                2 => Some(Swash::H120),
+                3 => Some(Swash::H140),
+                4 => Some(Swash::H90),
+                _ => return Err(Error::Invalid),
+            },
+            aileron: Source::decode(r)?,
+            elevator: Source::decode(r)?,
+            collective: Source::decode(r)?,
+            throttle: Source::decode(r)?,
+            aileron_weight: r.i16()?,
+            elevator_weight: r.i16()?,
+            collective_weight: r.i16()?,
+            ring: r.u8()?,
+            pitch_curves: Decode::decode(r)?,
+            throttle_curves: Decode::decode(r)?,
+        })
+    }
+}
+
+impl Encode for ChannelOrders {
+    fn encode(&self, w: &mut Writer) {
+        for order in [self.hid, self.crsf].iter() {
+            w.bytes(&order.0);
+        }
+    }
+}
+
+impl Decode for ChannelOrders {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        let mut order = || {
+            let mut order = ChannelOrder::AETR;
+            for i in order.0.iter_mut() {
+                *i = r.u8()?;
+            }
+            Some(order).filter(|o| o.is_valid()).ok_or(Error::Invalid)
+        };
+        Ok(Self {
+            hid: order()?,
+            crsf: order()?,
+        })
+    }
+}
+
+impl Encode for HidMapping {
+    fn encode(&self, w: &mut Writer) {
+        self.high.encode(w);
+        self.low.encode(w);
+        let (tag, i) = match self.target {
+            Target::Button(b) => (0, b),
+            Target::Buttons(b) => (1, b),
+            Target::Axis(axis) => (2, axis),
+            // the hat in the upper bits, older mappings are of the first one
+            Target::Hat { hat, direction } => (3, hat << 2 | direction as u8),
+            Target::Pulse(b) => (4, b),
+        };
+        w.u8(tag);
+        w.u8(i);
+    }
+}
+
+impl Decode for HidMapping {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        let (high, low) = (Switch::decode(r)?, Decode::decode(r)?);
+        let (tag, i) = (r.u8()?, r.u8()?);
+        let target = match tag {
+            0 => Target::Button(i),
+            1 => Target::Buttons(i),
+            2 => Target::Axis(i),
+            3 => Target::Hat {
+                hat: i >> 2,
+                direction: match i & 0x03 {
+                    0 => Direction::Up,
+                    1 => Direction::Right,
+                    2 => Direction::Down,
+                    _ => Direction::Left,
+                },
+            },
+            4 => Target::Pulse(i),
+            _ => return Err(Error::Invalid),
+        };
+        Some(Self { high, low, target })
+            .filter(HidMapping::is_valid)
+            .ok_or(Error::Invalid)
+    }
+}
+
+impl Encode for Layout {
+    fn encode(&self, w: &mut Writer) {
+        w.u8(self.axes);
+        w.u8(self.axis_bits);
+        w.u32(self.axis_max as u32);
+        w.u8(self.buttons);
+        w.u8(self.hats);
+        w.bool(self.status);
+    }
+}
+
+impl Decode for Layout {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        let layout = Self {
+            axes: r.u8()?,
+            axis_bits: r.u8()?,
+            axis_max: r.u32()? as i32,
+            buttons: r.u8()?,
+            hats: r.u8()?,
+            status: r.bool()?,
+        };
+        Some(layout).filter(Layout::is_valid).ok_or(Error::Invalid)
+    }
+}
+
+impl Encode for Limit {
+    fn encode(&self, w: &mut Writer) {
+        w.i16(self.subtrim);
+        w.i16(self.min);
+        w.i16(self.max);
+        w.u8(self.invert as u8 | (self.symmetrical as u8) << 1);
+        w.u16(self.ppm_center);
+    }
+}
+
+impl Decode for Limit {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        let (subtrim, min, max) = (r.i16()?, r.i16()?, r.i16()?);
+        let range = -Limit::MAX_ENDPOINT..=Limit::MAX_ENDPOINT;
+        if min > max || [subtrim, min, max].iter().any(|v| !range.contains(v)) {
+            return Err(Error::Invalid);
+        }
+        let flags = r.u8()?;
+        if flags & !0b11 != 0 {
+            return Err(Error::Invalid);
+        }
+        Ok(Self {
+            subtrim,
+            min,
+            max,
+            invert: flags & 1 != 0,
+            symmetrical: flags & 2 != 0,
+            ppm_center: r.u16()?,
+        })
+    }
+}
+
+impl Encode for Function {
+    fn encode(&self, w: &mut Writer) {
+        match *self {
+            Self::Off => w.u8(0),
+            Self::Greater(a, x)
+            | Self::Less(a, x)
+            | Self::AbsGreater(a, x)
+            | Self::AbsLess(a, x) => {
+                w.u8(match self {
+                    Self::Greater(..) => 1,
+                    Self::Less(..) => 2,
+                    Self::AbsGreater(..) => 3,
+                    _ => 4,
+                });
+                a.encode(w);
+                w.i16(x);
+            }
+            Self::SourceGreater(a, b) => {
+                w.u8(5);
+                a.encode(w);
+                b.encode(w);
+            }
+            Self::And(a, b) | Self::Or(a, b) | Self::Xor(a, b) => {
+                w.u8(match self {
+                    Self::And(..) => 6,
+                    Self::Or(..) => 7,
+                    _ => 8,
+                });
+                a.encode(w);
+                b.encode(w);
+            }
+            Self::Edge { switch, min, max } => {
+                w.u8(9);
+                switch.encode(w);
+                w.u16(min);
+                w.u16(max);
+            }
+            Self::Sticky { set, reset } => {
+                w.u8(10);
+                set.encode(w);
+                reset.encode(w);
+            }
+            Self::Timer { on, off } => {
+                w.u8(11);
+                w.u16(on);
+                w.u16(off);
+            }
+        }
+    }
+}
+
+impl Decode for Function {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => Self::Off,
+            1 => Self::Greater(Source::decode(r)?, r.i16()?),
+            2 => Self::Less(Source::decode(r)?, r.i16()?),
+            3 => Self::AbsGreater(Source::decode(r)?, r.i16()?),
+            4 => Self::AbsLess(Source::decode(r)?, r.i16()?),
+            5 => Self::SourceGreater(Source::decode(r)?, Source::decode(r)?),
+            6 => Self::And(Switch::decode(r)?, Switch::decode(r)?),
+            7 => Self::Or(Switch::decode(r)?, Switch::decode(r)?),
+            8 => Self::Xor(Switch::decode(r)?, Switch::decode(r)?),
+            9 => Self::Edge {
+                switch: Switch::decode(r)?,
+                min: r.u16()?,
+                max: r.u16()?,
+            },
+            10 => Self::Sticky {
+                set: Switch::decode(r)?,
+                reset: Switch::decode(r)?,
+            },
+            11 => Self::Timer {
+                on: r.u16()?,
+                off: r.u16()?,
+            },
+            _ => return Err(Error::Invalid),
+        })
+    }
+}
+
+impl Encode for LogicalSwitch {
+    fn encode(&self, w: &mut Writer) {
+        self.function.encode(w);
+        self.and.encode(w);
+        w.u16(self.delay);
+        w.u16(self.duration);
+    }
+}
+
+impl Decode for LogicalSwitch {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            function: Function::decode(r)?,
+            and: Condition::decode(r)?,
+            delay: r.u16()?,
+            duration: r.u16()?,
+        })
+    }
+}
+
+impl Encode for FlightMode {
+    fn encode(&self, w: &mut Writer) {
+        w.str(&self.name);
+        self.condition.encode(w);
+        w.u16(self.fade_in);
+        w.u16(self.fade_out);
+    }
+}
+
+impl Decode for FlightMode {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            name: r.str()?,
+            condition: Decode::decode(r)?,
+            fade_in: r.u16()?,
+            fade_out: r.u16()?,
+        })
+    }
+}
+
+impl Encode for FailsafeMode {
+    fn encode(&self, w: &mut Writer) {
+        match *self {
+            Self::Hold => w.u8(0),
+            Self::Custom(v) => {
+                w.u8(1);
+                w.i16(v);
+            }
+            Self::NoPulses => w.u8(2),
+        }
+    }
+}
+
+impl Decode for FailsafeMode {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => Self::Hold,
+            1 => Self::Custom(r.i16()?),
+            2 => Self::NoPulses,
+            _ => return Err(Error::Invalid),
+        })
+    }
+}
+
+impl Encode for ThrottleConfig {
+    fn encode(&self, w: &mut Writer) {
+        w.bool(self.channel.is_some());
+        if let Some(channel) = self.channel {
+            w.u8(channel);
+        }
+        self.stick.encode(w);
+        w.i16(self.low);
+        w.i16(self.safe);
+        self.cut.encode(w);
+        self.hold.encode(w);
+        w.i16(self.hold_value);
+        self.arm.encode(w);
+    }
+}
+
+impl Decode for ThrottleConfig {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            channel: if r.bool()? {
+                Some(r.index(CHANNELS)?)
+            } else {
+                None
+            },
+            stick: Source::decode(r)?,
+            low: r.i16()?,
+            safe: r.i16()?,
+            cut: Decode::decode(r)?,
+            hold: Decode::decode(r)?,
+            hold_value: r.i16()?,
+            arm: Decode::decode(r)?,
+        })
+    }
+}
+
+impl Encode for TimerConfig {
+    fn encode(&self, w: &mut Writer) {
+        match self.trigger {
+            Trigger::Off => w.u8(0),
+            Trigger::Above(source, x) => {
+                w.u8(1);
+                source.encode(w);
+                w.i16(x);
+            }
+            Trigger::Condition(condition) => {
+                w.u8(2);
+                condition.encode(w);
+            }
+            Trigger::Start(condition) => {
+                w.u8(3);
+                condition.encode(w);
+            }
+        }
+        w.u16(self.start);
+        w.u8(self.minute_beep as u8 | (self.persistent as u8) << 1);
+    }
+}
+
+impl Decode for TimerConfig {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        let trigger = match r.u8()? {
+            0 => Trigger::Off,
+            1 => Trigger::Above(Source::decode(r)?, r.i16()?),
+            2 => Trigger::Condition(Condition::decode(r)?),
+            3 => Trigger::Start(Condition::decode(r)?),
+            _ => return Err(Error::Invalid),
+        };
+        let start = r.u16()?;
+        let flags = r.u8()?;
+        if flags & !0b11 != 0 {
+            return Err(Error::Invalid);
+        }
+        Ok(Self {
+            trigger,
+            start,
+            minute_beep: flags & 1 != 0,
+            persistent: flags & 2 != 0,
+        })
+    }
+}
+
+impl Encode for Action {
+    fn encode(&self, w: &mut Writer) {
+        match *self {
+            Self::None => w.u8(0),
+            Self::ResetTimer(timer) => {
+                w.u8(1);
+                w.u8(timer);
+            }
+            Self::Beep(beep) => {
+                w.u8(2);
+                w.u8(match beep {
+                    Beep::Short => 0,
+                    Beep::Long => 1,
+                });
+            }
+            Self::Override { channel, value } => {
+                w.u8(3);
+                w.u8(channel);
+                w.i16(value);
+            }
+            Self::CenterTrims => w.u8(4),
+            Self::Record => w.u8(5),
+            Self::Button(button) => {
+                w.u8(6);
+                w.u8(button);
+            }
+            Self::Crsf {
+                realm,
+                command,
+                value,
+            } => {
+                w.u8(7);
+                w.bytes(&[realm, command, value]);
+            }
+        }
+    }
+}
+
+impl Decode for Action {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => Self::None,
+            1 => Self::ResetTimer(r.index(TIMERS)?),
+            2 => Self::Beep(match r.u8()? {
+                0 => Beep::Short,
+                1 => Beep::Long,
+                _ => return Err(Error::Invalid),
+            }),
+            3 => Self::Override {
+                channel: r.index(CHANNELS)?,
+                value: r.i16()?,
+            },
+            4 => Self::CenterTrims,
+            5 => Self::Record,
+            6 => Self::Button(r.index(HID_BUTTONS)?),
+            7 => Self::Crsf {
+                realm: r.u8()?,
+                command: r.u8()?,
+                value: r.u8()?,
+            },
+            _ => return Err(Error::Invalid),
+        })
+    }
+}
+
+impl Encode for SpecialFunction {
+    fn encode(&self, w: &mut Writer) {
+        self.condition.encode(w);
+        self.action.encode(w);
+        w.bool(self.edge);
+        w.u16(self.repeat);
+    }
+}
+
+impl Decode for SpecialFunction {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            condition: Condition::decode(r)?,
+            action: Action::decode(r)?,
+            edge: r.bool()?,
+            repeat: r.u16()?,
+        })
+    }
+}
+
+impl<T: Encode, const N: usize> Encode for [T; N] {
+    fn encode(&self, w: &mut Writer) {
+        for v in self {
+            v.encode(w);
+        }
+    }
+}
+
+impl<T: Decode + Default, const N: usize> Decode for [T; N] {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        let mut array: [T; N] = core::array::from_fn(|_| T::default());
+        for v in array.iter_mut() {
+            *v = T::decode(r)?;
+        }
+        Ok(array)
+    }
+}
+
+impl Encode for i16 {
+    fn encode(&self, w: &mut Writer) {
+        w.i16(*self);
+    }
+}
+
+impl Decode for i16 {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        r.i16()
+    }
+}
+
+impl Encode for u16 {
+    fn encode(&self, w: &mut Writer) {
+        w.u16(*self);
+    }
+}
+
+impl Decode for u16 {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        r.u16()
+    }
+}
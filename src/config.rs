@@ -0,0 +1,725 @@
+//! Device configuration and the format of the settings record
+//!
+//! The record is `[b'R', b'C', VERSION: u8, length: u16, config, models]`, see [`Models::save`]
+//! for the models. Bump [`VERSION`] whenever an encoding changes and add a step to [`load`] which
+//! brings the older records up to date. Anything failing validation falls back to its defaults.
+use heapless::String;
+
+use crate::codec::{Decode, Encode, Error, Reader, Writer};
+use crate::encoder::EncoderMapping;
+use crate::flight_modes::FLIGHT_MODES;
+use crate::gestures::{Gesture, Kind, GESTURES};
+use crate::globals::GLOBALS;
+use crate::hid_map::{HidMapping, HID_MAPPINGS};
+use crate::inputs::LinearInput;
+use crate::mixer::Switch;
+use crate::models::{self, Model, Models, MAX_MODELS};
+use crate::sticks::StickMode;
+use crate::storage::{Flash, Storage, SECTOR_SIZE};
+use crate::trims::{TrimConfig, TrimMode, TRIMS};
+use crate::types::{Value, OLD_VALUE_MAX, VALUE_MAX};
+
+/// Analog inputs, read by the ADC or the angle sensors
+pub const ANALOG_PINS: usize = 6;
+pub const DIGITAL_PINS: usize = 10;
+pub const ENCODERS: usize = 1;
+/// Buttons of the HID report
+pub const HID_BUTTONS: usize = 128;
+
+/// Current version of the settings record
+///
+/// - `0`: only the trims of the single model, without and later with flight modes
+/// - `1`: only the models
+/// - `2`: versioned, device configuration and models
+/// - `3`: failsafe of the models
+/// - `4`: throttle interlocks of the models
+/// - `5`: timers of the models
+/// - `6`: special functions of the models
+/// - `7`: global variables of the models
+/// - `8`: delay and slow of the mix lines and channels
+/// - `9`: heli mixing of the models
+/// - `10`: stick mode of the device, channel orders of the models
+/// - `11`: HID mappings of the models instead of the HID buttons of the device
+/// - `12`: gestures of the device
+/// - `13`: HID report layout of the models
+/// - `14`: higher resolution of the values of axes and trims, see [`rescale`]
+/// - `15`: centers of the angle sensors
+pub const VERSION: u8 = 15;
+const MAGIC: [u8; 2] = *b"RC";
+const HEADER: usize = 5;
+/// Largest encoded device configuration
+pub const CONFIG_SIZE: usize = 512;
+// keep the erases of the settings sector rare, even if every model is used to its bounds
+const _: () = assert!(4 * (HEADER + CONFIG_SIZE + models::RECORD_SIZE) <= SECTOR_SIZE);
+
+/// Value of an axis or trim of a settings record before version 14 at the current resolution
+pub fn rescale(v: i16) -> i16 {
+    num::clamp(
+        v as Value * VALUE_MAX / OLD_VALUE_MAX,
+        i16::MIN as Value,
+        i16::MAX as Value,
+    ) as i16
+}
+
+/// Identity of the device on the USB
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsbIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: String<32>,
+    pub product: String<32>,
+}
+
+impl Default for UsbIdentity {
+    fn default() -> Self {
+        let mut identity = Self {
+            // https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt
+            // For USB Joystick as there is no USB Game Pad on this free ID list
+            vid: 0x16c0,
+            pid: 0x27dc,
+            manufacturer: String::new(),
+            product: String::new(),
+        };
+        identity.manufacturer.push_str("autumnal.de").ok();
+        identity.product.push_str("RC USB Controller").ok();
+        identity
+    }
+}
+
+/// Settings of the device, shared by all models
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub calibration: [LinearInput; ANALOG_PINS],
+    /// Raw angle of the stick center of the inputs read by angle sensors, the calibration is
+    /// relative to it
+    pub angle_centers: [Option<u16>; ANALOG_PINS],
+    pub trims: [TrimConfig; TRIMS],
+    pub encoders: [EncoderMapping; ENCODERS],
+    pub usb: UsbIdentity,
+    /// Assignment of the gimbal axes to the first axes, `None` keeps the order of the pins
+    pub stick_mode: Option<StickMode>,
+    pub gestures: [Option<Gesture>; GESTURES],
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let trim = |up, down| TrimConfig {
+            mode: TrimMode::Offset,
+            pins: (up, down),
+            step: (VALUE_MAX / 250) as i16,
+        };
+        Self {
+            calibration: Default::default(),
+            angle_centers: [None; ANALOG_PINS],
+            trims: [trim(2, 3), trim(4, 5), trim(6, 7), trim(8, 9)],
+            encoders: [EncoderMapping::Buttons { up: 6, down: 7 }],
+            usb: Default::default(),
+            stick_mode: None,
+            gestures: [None; GESTURES],
+        }
+    }
+}
+
+impl Encode for LinearInput {
+    fn encode(&self, w: &mut Writer) {
+        match *self {
+            Self::NoCalibration => w.u8(0),
+            Self::OngoingCalibration { start, end } => {
+                w.u8(1);
+                w.u16(start);
+                w.u16(end);
+            }
+            Self::Calibrated { start, mid, end } => {
+                w.u8(2);
+                w.u16(start);
+                w.u16(mid);
+                w.u16(end);
+            }
+        }
+    }
+}
+
+impl Decode for LinearInput {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        let input = match r.u8()? {
+            0 => Self::NoCalibration,
+            1 => Self::OngoingCalibration {
+                start: r.u16()?,
+                end: r.u16()?,
+            },
+            2 => Self::Calibrated {
+                start: r.u16()?,
+                mid: r.u16()?,
+                end: r.u16()?,
+            },
+            _ => return Err(Error::Invalid),
+        };
+        match input {
+            Self::OngoingCalibration { start, end } if start > end => Err(Error::Invalid),
+            Self::Calibrated { start, mid, end } if start > mid || mid > end => Err(Error::Invalid),
+            input => Ok(input),
+        }
+    }
+}
+
+impl Encode for TrimConfig {
+    fn encode(&self, w: &mut Writer) {
+        match self.mode {
+            TrimMode::Offset => w.u8(0),
+            TrimMode::Buttons { up, down } => {
+                w.u8(1);
+                w.u8(up as u8);
+                w.u8(down as u8);
+            }
+            TrimMode::Global(index) => {
+                w.u8(2);
+                w.u8(index);
+            }
+        }
+        w.u8(self.pins.0 as u8);
+        w.u8(self.pins.1 as u8);
+        w.i16(self.step);
+    }
+}
+
+impl Decode for TrimConfig {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        let mode = match r.u8()? {
+            0 => TrimMode::Offset,
+            1 => TrimMode::Buttons {
+                up: r.index(HID_BUTTONS)? as usize,
+                down: r.index(HID_BUTTONS)? as usize,
+            },
+            2 => TrimMode::Global(r.index(GLOBALS)?),
+            _ => return Err(Error::Invalid),
+        };
+        Ok(Self {
+            mode,
+            pins: (
+                r.index(DIGITAL_PINS)? as usize,
+                r.index(DIGITAL_PINS)? as usize,
+            ),
+            step: r.i16()?,
+        })
+    }
+}
+
+impl Encode for EncoderMapping {
+    fn encode(&self, w: &mut Writer) {
+        match *self {
+            Self::Axis {
+                axis,
+                step,
+                min,
+                max,
+            } => {
+                w.u8(0);
+                w.u8(axis as u8);
+                w.i16(step);
+                w.i16(min);
+                w.i16(max);
+            }
+            Self::Buttons { up, down } => {
+                w.u8(1);
+                w.u8(up as u8);
+                w.u8(down as u8);
+            }
+            Self::Trim { axis } => {
+                w.u8(2);
+                w.u8(axis as u8);
+            }
+            Self::Global { index, step } => {
+                w.u8(3);
+                w.u8(index);
+                w.i16(step);
+            }
+        }
+    }
+}
+
+impl Decode for EncoderMapping {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => {
+                let axis = r.index(ANALOG_PINS)? as usize;
+                let (step, min, max) = (r.i16()?, r.i16()?, r.i16()?);
+                if min > max {
+                    return Err(Error::Invalid);
+                }
+                Self::Axis {
+                    axis,
+                    step,
+                    min,
+                    max,
+                }
+            }
+            1 => Self::Buttons {
+                up: r.index(HID_BUTTONS)? as usize,
+                down: r.index(HID_BUTTONS)? as usize,
+            },
+            2 => Self::Trim {
+                axis: r.index(TRIMS)? as usize,
+            },
+            3 => Self::Global {
+                index: r.index(GLOBALS)?,
+                step: r.i16()?,
+            },
+            _ => return Err(Error::Invalid),
+        })
+    }
+}
+
+impl Encode for Gesture {
+    fn encode(&self, w: &mut Writer) {
+        w.u8(self.pin);
+        w.u8(match self.kind {
+            Kind::Short => 0,
+            Kind::Long => 1,
+            Kind::Double => 2,
+            Kind::Toggle => 3,
+        });
+    }
+}
+
+impl Decode for Gesture {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            pin: r.index(DIGITAL_PINS)?,
+            kind: match r.u8()? {
+                0 => Kind::Short,
+                1 => Kind::Long,
+                2 => Kind::Double,
+                3 => Kind::Toggle,
+                _ => return Err(Error::Invalid),
+            },
+        })
+    }
+}
+
+impl Encode for Config {
+    fn encode(&self, w: &mut Writer) {
+        self.calibration.encode(w);
+        for trim in self.trims.iter() {
+            trim.encode(w);
+        }
+        for encoder in self.encoders.iter() {
+            encoder.encode(w);
+        }
+        w.u16(self.usb.vid);
+        w.u16(self.usb.pid);
+        w.str(&self.usb.manufacturer);
+        w.str(&self.usb.product);
+        w.u8(match self.stick_mode {
+            None => 0,
+            Some(StickMode::Mode1) => 1,
+            Some(StickMode::Mode2) => 2,
+            Some(StickMode::Mode3) => 3,
+            Some(StickMode::Mode4) => 4,
+        });
+        self.gestures.encode(w);
+        self.angle_centers.encode(w);
+    }
+}
+
+impl Decode for Config {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Self::decode_version(r, VERSION)
+    }
+}
+
+/// HID buttons of the device before version 11, each pressed while its switch is on
+type HidButtons = [Option<Switch>; 8];
+
+impl Config {
+    /// Decode the configuration of a settings record of `version`
+    pub fn decode_version(r: &mut Reader, version: u8) -> Result<Self, Error> {
+        Self::decode_record(r, version).map(|(config, _)| config)
+    }
+
+    /// Decode the configuration of a settings record of `version` and the parts of it which moved
+    /// to the models
+    fn decode_record(r: &mut Reader, version: u8) -> Result<(Self, Option<HidButtons>), Error> {
+        let mut config = Self {
+            calibration: Decode::decode(r)?,
+            ..Default::default()
+        };
+        for trim in config.trims.iter_mut() {
+            *trim = TrimConfig::decode(r)?;
+            if version < 14 && trim.mode == TrimMode::Offset {
+                trim.step = rescale(trim.step);
+            }
+        }
+        for encoder in config.encoders.iter_mut() {
+            *encoder = EncoderMapping::decode(r)?;
+            if let EncoderMapping::Axis { step, min, max, .. } = encoder {
+                if version < 14 {
+                    *step = rescale(*step);
+                    *min = rescale(*min);
+                    *max = rescale(*max);
+                }
+            }
+        }
+        let hid_buttons = match version {
+            0..=10 => Some(Decode::decode(r)?),
+            _ => None,
+        };
+        config.usb = UsbIdentity {
+            vid: r.u16()?,
+            pid: r.u16()?,
+            manufacturer: r.str()?,
+            product: r.str()?,
+        };
+        if version >= 10 {
+            config.stick_mode = match r.u8()? {
+                0 => None,
+                1 => Some(StickMode::Mode1),
+                2 => Some(StickMode::Mode2),
+                3 => Some(StickMode::Mode3),
+                4 => Some(StickMode::Mode4),
+                _ => return Err(Error::Invalid),
+            };
+        }
+        if version >= 12 {
+            config.gestures = Decode::decode(r)?;
+        }
+        if version >= 15 {
+            config.angle_centers = Decode::decode(r)?;
+        }
+        Ok((config, hid_buttons))
+    }
+}
+
+/// HID buttons of the firmware before version 2, which had no device configuration
+const FIXED_HID_BUTTONS: HidButtons = [
+    Some(Switch::Input(0)),
+    Some(Switch::Input(1)),
+    Some(Switch::Logical(0)),
+    Some(Switch::Logical(1)),
+    Some(Switch::Logical(2)),
+    Some(Switch::Logical(3)),
+    None,
+    None,
+];
+
+/// Version of a settings record
+///
+/// The records before version 2 have no header. Those of version 1 start with the active model
+/// and the number of memories, where version 0 has the high byte of the first trim, which is
+/// never that large. Version 0 is told by its length.
+fn version(payload: &[u8]) -> Option<u8> {
+    match *payload {
+        [m0, m1, version, _, _, ..] if [m0, m1] == MAGIC => Some(version),
+        [active, count, ..] if (active as usize) < MAX_MODELS && count as usize == MAX_MODELS => {
+            Some(1)
+        }
+        _ if payload.len() == TRIMS * 2 || payload.len() == FLIGHT_MODES * TRIMS * 2 => Some(0),
+        _ => None,
+    }
+}
+
+/// Configuration and models from a settings record of any known version
+pub fn load(payload: &[u8]) -> (Config, Models) {
+    let (config, mut models, hid_buttons) = match version(payload) {
+        Some(version @ 2..=VERSION) => {
+            let len = u16::from_le_bytes([payload[3], payload[4]]) as usize;
+            let (config, models) = payload[HEADER..].split_at(len.min(payload.len() - HEADER));
+            let mut r = Reader::new(config);
+            let (config, hid_buttons) = Config::decode_record(&mut r, version)
+                .ok()
+                .filter(|_| r.is_done())
+                .unwrap_or_default();
+            let models = Models::load(models, version).unwrap_or_default();
+            (config, models, hid_buttons)
+        }
+        // the device configuration did not exist yet
+        Some(1) => (
+            Config::default(),
+            Models::load(payload, 1).unwrap_or_default(),
+            Some(FIXED_HID_BUTTONS),
+        ),
+        // the trims of the single model
+        Some(0) => {
+            let mut model = Model::default();
+            for (trim, bytes) in model.trims.iter_mut().flatten().zip(payload.chunks(2)) {
+                *trim = rescale(i16::from_le_bytes([bytes[0], bytes[1]]));
+            }
+            let mut models = Models::default();
+            models.store(&model).ok();
+            (Config::default(), models, Some(FIXED_HID_BUTTONS))
+        }
+        // unknown or newer, do not try to make sense of it
+        _ => return Default::default(),
+    };
+    if let Some(buttons) = hid_buttons {
+        // the buttons moved to the HID mappings of every model
+        models
+            .update_all(|model| {
+                model.hid.config = [None; HID_MAPPINGS];
+                for (i, (mapping, switch)) in
+                    model.hid.config.iter_mut().zip(buttons.iter()).enumerate()
+                {
+                    *mapping = switch.map(|switch| HidMapping::button(switch, i as u8));
+                }
+            })
+            .ok();
+    }
+    (config, models)
+}
+
+/// Write the configuration and all models as the current version
+pub fn save<F: Flash>(
+    config: &Config,
+    models: &Models,
+    storage: &mut Storage<F>,
+) -> Result<(), F::Error> {
+    let mut buf = [0; HEADER + CONFIG_SIZE];
+    let mut w = Writer::new(&mut buf[HEADER..]);
+    config.encode(&mut w);
+    // the configuration is bounded, it always fits
+    let len = w.finish().unwrap_or(0);
+    buf[..2].copy_from_slice(&MAGIC);
+    buf[2] = VERSION;
+    buf[3..HEADER].copy_from_slice(&(len as u16).to_le_bytes());
+    models.save(storage, &buf[..HEADER + len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::RamFlash;
+
+    /// Configuration using the fields of every version
+    fn full() -> Config {
+        let mut config = Config::default();
+        config.calibration[1] = LinearInput::Calibrated {
+            start: 100,
+            mid: 2000,
+            end: 4000,
+        };
+        config.trims[3].mode = TrimMode::Buttons { up: 10, down: 11 };
+        config.trims[3].step = 7;
+        config.encoders[0] = EncoderMapping::Axis {
+            axis: 5,
+            step: rescale(10),
+            min: rescale(-250),
+            max: rescale(500),
+        };
+        config.usb.pid = 0x1234;
+        config.stick_mode = Some(StickMode::Mode1);
+        config.gestures[2] = Some(Gesture {
+            pin: 9,
+            kind: Kind::Double,
+        });
+        config.angle_centers[3] = Some(0x3ff0);
+        config
+    }
+
+    /// Axis or trim value as a settings record before version 14 stored it
+    fn unscale(v: i16) -> i16 {
+        (v as f32 * OLD_VALUE_MAX as f32 / VALUE_MAX as f32).round() as i16
+    }
+
+    /// Encoding of `config` by the firmware writing settings records of `version`
+    fn encode_version(config: &Config, version: u8) -> std::vec::Vec<u8> {
+        let mut config = config.clone();
+        if version < 14 {
+            for trim in config.trims.iter_mut() {
+                if trim.mode == TrimMode::Offset {
+                    trim.step = unscale(trim.step);
+                }
+            }
+            for encoder in config.encoders.iter_mut() {
+                if let EncoderMapping::Axis { step, min, max, .. } = encoder {
+                    *step = unscale(*step);
+                    *min = unscale(*min);
+                    *max = unscale(*max);
+                }
+            }
+        }
+        let mut buf = [0; CONFIG_SIZE];
+        let mut w = Writer::new(&mut buf);
+        config.encode(&mut w);
+        let len = w.finish().unwrap();
+        let mut bytes = buf[..len].to_vec();
+        if version < 15 {
+            let centers = config.angle_centers.iter();
+            bytes.truncate(
+                bytes.len() - centers.map(|c| 1 + 2 * c.is_some() as usize).sum::<usize>(),
+            );
+        }
+        if version < 12 {
+            let mut gestures = [0; 3 * GESTURES];
+            let mut w = Writer::new(&mut gestures);
+            config.gestures.encode(&mut w);
+            bytes.truncate(bytes.len() - w.finish().unwrap());
+        }
+        if version < 10 {
+            bytes.pop();
+        }
+        if version < 11 {
+            // the HID buttons of the device followed the encoders
+            let usb = 6 + config.usb.manufacturer.len() + config.usb.product.len();
+            let usb = bytes.len() - usb - (version >= 10) as usize;
+            let mut buttons = [None; 8];
+            buttons[0] = Some(Switch::Input(4));
+            let mut buf = [0; 32];
+            let mut w = Writer::new(&mut buf);
+            buttons.encode(&mut w);
+            let len = w.finish().unwrap();
+            bytes.splice(usb..usb, buf[..len].iter().copied());
+        }
+        bytes
+    }
+
+    /// Settings record of `version` with the configuration and the first model
+    fn record(config: &[u8], version: u8, model: &[u8]) -> std::vec::Vec<u8> {
+        let mut record = vec![MAGIC[0], MAGIC[1], version];
+        record.extend_from_slice(&(config.len() as u16).to_le_bytes());
+        record.extend_from_slice(config);
+        record.extend_from_slice(&[0, models::MAX_MODELS as u8]);
+        record.extend_from_slice(&(model.len() as u16).to_le_bytes());
+        record.extend_from_slice(model);
+        record.extend_from_slice(&[0; 2 * (models::MAX_MODELS - 1)]);
+        record
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let bytes = encode_version(&full(), VERSION);
+        let mut r = Reader::new(&bytes);
+        assert_eq!(Config::decode(&mut r), Ok(full()));
+        assert!(r.is_done());
+    }
+
+    #[test]
+    fn configs_of_every_version() {
+        for version in 2..=VERSION {
+            let mut expected = full();
+            if version < 10 {
+                expected.stick_mode = None;
+            }
+            if version < 12 {
+                expected.gestures = [None; GESTURES];
+            }
+            if version < 15 {
+                expected.angle_centers = [None; ANALOG_PINS];
+            }
+            let (config, _) = load(&record(&encode_version(&full(), version), version, &[]));
+            assert_eq!(config, expected, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn unknown_records() {
+        let bytes = encode_version(&full(), VERSION);
+        // newer, without a header or of an unknown length
+        for payload in [
+            record(&bytes, VERSION + 1, &[]),
+            record(&bytes, VERSION, &[])[2..].to_vec(),
+            vec![0; 2 * TRIMS + 2],
+        ]
+        .iter()
+        {
+            let (config, models) = load(payload);
+            assert_eq!(config, Config::default());
+            assert_eq!(models.active(), 0);
+        }
+        // a broken configuration keeps the models
+        let mut payload = record(&bytes, VERSION, &[]);
+        payload[HEADER] = 3;
+        payload[HEADER + bytes.len()] = 2;
+        let (config, models) = load(&payload);
+        assert_eq!(config, Config::default());
+        assert_eq!(models.active(), 2);
+    }
+
+    /// Settings loaded from what the current firmware saves of them
+    fn resaved(config: &Config, models: &Models) -> (Config, Models) {
+        let mut storage = Storage::new(RamFlash::new(SECTOR_SIZE));
+        save(config, models, &mut storage).unwrap();
+        load(storage.load().unwrap())
+    }
+
+    fn fixed_hid_mappings() -> [Option<HidMapping>; HID_MAPPINGS] {
+        let mut mappings = [None; HID_MAPPINGS];
+        for (i, switch) in FIXED_HID_BUTTONS.iter().enumerate() {
+            mappings[i] = switch.map(|switch| HidMapping::button(switch, i as u8));
+        }
+        mappings
+    }
+
+    #[test]
+    fn trims_of_version_0() {
+        for modes in [1, FLIGHT_MODES].iter() {
+            let trims: std::vec::Vec<i16> = (0..modes * TRIMS).map(|i| 3 * i as i16 - 20).collect();
+            let payload: std::vec::Vec<u8> = trims.iter().flat_map(|t| t.to_le_bytes()).collect();
+            assert_eq!(version(&payload), Some(0));
+            let mut expected = [[0; TRIMS]; FLIGHT_MODES];
+            for (expected, trim) in expected.iter_mut().flatten().zip(trims.iter()) {
+                *expected = rescale(*trim);
+            }
+
+            let (config, models) = load(&payload);
+            assert_eq!(config, Config::default());
+            let (config, models) = resaved(&config, &models);
+            assert_eq!(config, Config::default());
+            assert_eq!(models.active(), 0);
+            let model = models.model(0);
+            assert_eq!(model.trims, expected, "{} flight modes", modes);
+            assert_eq!(model.hid.config, fixed_hid_mappings());
+        }
+    }
+
+    #[test]
+    fn models_of_version_1() {
+        let model = models::tests::encode_version(&models::tests::full(), 1);
+        let mut expected = Model::decode_version(&mut Reader::new(&model), 1).unwrap();
+        expected.hid.config = fixed_hid_mappings();
+        // the models of memories 0 and 3, the latter active
+        let mut payload = vec![3, MAX_MODELS as u8];
+        for i in 0..MAX_MODELS {
+            let image = if i == 0 || i == 3 { &model[..] } else { &[] };
+            payload.extend_from_slice(&(image.len() as u16).to_le_bytes());
+            payload.extend_from_slice(image);
+        }
+        assert_eq!(version(&payload), Some(1));
+
+        let (config, models) = load(&payload);
+        assert_eq!(config, Config::default());
+        let (config, models) = resaved(&config, &models);
+        assert_eq!(config, Config::default());
+        assert_eq!(models.active(), 3);
+        for i in [0, 3].iter() {
+            assert_eq!(
+                models::tests::encoding(&models.model(*i)),
+                models::tests::encoding(&expected)
+            );
+        }
+    }
+
+    #[test]
+    fn hid_buttons_move_to_the_models() {
+        for version in 2..=VERSION {
+            let model = models::tests::encode_version(&models::tests::full(), version);
+            let payload = record(&encode_version(&full(), version), version, &model);
+            let model = load(&payload).1.model(0);
+            let mut expected = [None; HID_MAPPINGS];
+            expected[0] = Some(HidMapping::button(Switch::Input(4), 0));
+            if version >= 11 {
+                expected = models::tests::full().hid.config;
+            }
+            assert_eq!(model.hid.config, expected, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn saved_settings() {
+        let mut storage = Storage::new(RamFlash::new(SECTOR_SIZE));
+        let mut models = Models::default();
+        models.set_active(5);
+        save(&full(), &models, &mut storage).unwrap();
+        let (config, models) = load(storage.load().unwrap());
+        assert_eq!(config, full());
+        assert_eq!(models.active(), 5);
+    }
+}
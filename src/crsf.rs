@@ -0,0 +1,152 @@
+//! Output to a CRSF (Crossfire/ExpressLRS) transmitter module
+//!
+//! Frames are `[address, length, type, payload.., crc]`, where the length counts the type, the
+//! payload and the CRC.
+use embedded_hal::serial;
+use heapless::Vec;
+
+use crate::mixer::CHANNELS;
+use crate::types::{Value, VALUE_MAX};
+
+/// Baud rate of the module port
+pub const BAUDRATE: u32 = 400_000;
+/// Largest frame including address, length and CRC
+pub const MAX_FRAME: usize = 64;
+
+/// Address of the transmitter module
+const ADDRESS_MODULE: u8 = 0xee;
+/// Address of the handset, i.e. us
+const ADDRESS_HANDSET: u8 = 0xea;
+const TYPE_RC_CHANNELS: u8 = 0x16;
+const TYPE_FLIGHT_MODE: u8 = 0x21;
+const TYPE_COMMAND: u8 = 0x32;
+
+/// Channel value at the center, `1500 µs`
+const CHANNEL_CENTER: Value = 992;
+/// Channel value from the center to full travel, `±512 µs`
+const CHANNEL_RANGE: Value = 819;
+const CHANNEL_BITS: u32 = 11;
+
+pub type Frame = Vec<u8, MAX_FRAME>;
+
+/// CRC-8 with the polynomial `poly`
+fn crc8_poly(poly: u8, data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                crc << 1 ^ poly
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// CRC-8/DVB-S2 over the type and payload
+fn crc8(data: &[u8]) -> u8 {
+    crc8_poly(0xd5, data)
+}
+
+fn frame(kind: u8, payload: &[u8]) -> Frame {
+    let mut frame = Frame::new();
+    let len = payload.len().min(MAX_FRAME - 4);
+    frame.push(ADDRESS_MODULE).ok();
+    frame.push(len as u8 + 2).ok();
+    frame.push(kind).ok();
+    frame.extend_from_slice(&payload[..len]).ok();
+    let crc = crc8(&frame[2..]);
+    frame.push(crc).ok();
+    frame
+}
+
+/// Packed channels, 11 bits each and least significant bit first
+pub fn rc_channels(channels: &[Value; CHANNELS]) -> Frame {
+    let mut payload = [0u8; CHANNELS * CHANNEL_BITS as usize / 8];
+    let (mut bits, mut acc, mut i) = (0, 0u32, 0);
+    for channel in channels.iter() {
+        let v = num::clamp(
+            CHANNEL_CENTER + channel * CHANNEL_RANGE / VALUE_MAX,
+            0,
+            (1 << CHANNEL_BITS) - 1,
+        );
+        acc |= (v as u32) << bits;
+        bits += CHANNEL_BITS;
+        while bits >= 8 {
+            payload[i] = acc as u8;
+            acc >>= 8;
+            bits -= 8;
+            i += 1;
+        }
+    }
+    frame(TYPE_RC_CHANNELS, &payload)
+}
+
+/// Name of the flight mode, null terminated
+pub fn flight_mode(name: &str) -> Frame {
+    let mut payload = Vec::<u8, { MAX_FRAME - 4 }>::new();
+    payload
+        .extend_from_slice(&name.as_bytes()[..name.len().min(MAX_FRAME - 5)])
+        .ok();
+    payload.push(0).ok();
+    frame(TYPE_FLIGHT_MODE, &payload)
+}
+
+/// Command to the module, `[destination, origin, realm, command, payload.., command crc]`
+pub fn command(realm: u8, command: u8, payload: &[u8]) -> Frame {
+    let mut data = Vec::<u8, { MAX_FRAME - 4 }>::new();
+    data.push(TYPE_COMMAND).ok();
+    data.extend_from_slice(&[ADDRESS_MODULE, ADDRESS_HANDSET, realm, command])
+        .ok();
+    data.extend_from_slice(&payload[..payload.len().min(MAX_FRAME - 10)])
+        .ok();
+    // the command has its own CRC over the type and the payload
+    let crc = crc8_poly(0xba, &data);
+    data.push(crc).ok();
+    frame(TYPE_COMMAND, &data[1..])
+}
+
+/// Interrupt driven sending of frames
+pub struct Sender<TX> {
+    tx: TX,
+    frame: Frame,
+    pos: usize,
+}
+
+impl<TX: serial::Write<u8>> Sender<TX> {
+    pub fn new(tx: TX) -> Self {
+        Self {
+            tx,
+            frame: Frame::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn tx(&mut self) -> &mut TX {
+        &mut self.tx
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.pos < self.frame.len()
+    }
+
+    /// Start sending a frame, dropped if the last one is not sent yet
+    pub fn send(&mut self, frame: Frame) -> bool {
+        if self.is_busy() {
+            return false;
+        }
+        self.frame = frame;
+        self.pos = 0;
+        self.poll();
+        true
+    }
+
+    /// Hand as many bytes to the UART as it takes, call once it is ready for more
+    pub fn poll(&mut self) {
+        while let Some(byte) = self.frame.get(self.pos) {
+            if self.tx.write(*byte).is_err() {
+                break;
+            }
+            self.pos += 1;
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Response curves of mix lines
+use heapless::Vec;
+
+use crate::globals::{Param, GLOBAL_LIMIT};
+use crate::types::{Value, VALUE_MAX};
+
+/// Maximum number of custom curves
+pub const MAX_CURVES: usize = 8;
+/// Maximum number of points of a custom curve
+pub const MAX_POINTS: usize = 9;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Curve {
+    /// Linear
+    #[default]
+    None,
+    /// Exponential in percent, positive values soften the center
+    Expo(Param),
+    /// Differential in percent, positive values reduce the negative side
+    Diff(Param),
+    /// Absolute value
+    Abs,
+    /// Custom curve by index
+    Custom(u8),
+}
+
+/// Custom curve of equally spaced points from `-VALUE_MAX` to `VALUE_MAX`, in percent up to
+/// `GLOBAL_LIMIT`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PointCurve {
+    pub points: Vec<Param, MAX_POINTS>,
+}
+
+impl PointCurve {
+    /// Linear interpolation between the points, `globals` are the global variables
+    pub fn apply(&self, x: Value, globals: &[i16]) -> Value {
+        let points = &self.points;
+        if points.len() < 2 {
+            return x;
+        }
+
+        let segments = (points.len() - 1) as i32;
+        let width = 2 * VALUE_MAX;
+        let pos = (num::clamp(x, -VALUE_MAX, VALUE_MAX) + VALUE_MAX) * segments;
+        let i = ((pos / width) as usize).min(points.len() - 2);
+        let dx = pos - i as i32 * width;
+
+        let y = |point: Param| {
+            let percent = num::clamp(point.get(globals), -GLOBAL_LIMIT, GLOBAL_LIMIT);
+            percent as i64 * VALUE_MAX as i64 / 100
+        };
+        let (y0, y1) = (y(points[i]), y(points[i + 1]));
+        (y0 + (y1 - y0) * dx as i64 / width as i64) as Value
+    }
+}
+
+impl Curve {
+    /// Apply the curve to `x`, custom curves are looked up in `curves`
+    pub fn apply(self, x: Value, curves: &[PointCurve], globals: &[i16]) -> Value {
+        match self {
+            Self::None => x,
+            Self::Expo(k) => expo(x, k.get(globals) as i32),
+            Self::Diff(d) => {
+                let d = num::clamp(d.get(globals) as i32, -100, 100);
+                if (x < 0 && d > 0) || (x > 0 && d < 0) {
+                    x * (100 - d.abs()) / 100
+                } else {
+                    x
+                }
+            }
+            Self::Abs => x.abs(),
+            Self::Custom(i) => curves.get(i as usize).map_or(x, |c| c.apply(x, globals)),
+        }
+    }
+}
+
+/// Cubic expo, `k` in percent: `y = (k * x³ + (100 - k) * x) / 100` with `x` normalized
+pub fn expo(x: Value, k: i32) -> Value {
+    let k = num::clamp(k, -100, 100) as i64;
+    let x = x as i64;
+    let max = VALUE_MAX as i64;
+    (x * (k * x * x + (100 - k) * max * max) / (100 * max * max)) as Value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(points: &[i16]) -> PointCurve {
+        PointCurve {
+            points: points.iter().map(|p| Param::Value(*p)).collect(),
+        }
+    }
+
+    #[test]
+    fn expo_keeps_the_ends() {
+        for k in [-100, 0, 40, 100] {
+            assert_eq!(expo(VALUE_MAX, k), VALUE_MAX);
+            assert_eq!(expo(-VALUE_MAX, k), -VALUE_MAX);
+            assert_eq!(expo(0, k), 0);
+        }
+        assert!(expo(VALUE_MAX / 2, 100) < VALUE_MAX / 2);
+    }
+
+    #[test]
+    fn points_are_interpolated() {
+        let c = curve(&[-100, 0, 100]);
+        assert_eq!(c.apply(-VALUE_MAX, &[]), -VALUE_MAX);
+        assert_eq!(c.apply(VALUE_MAX / 2, &[]), VALUE_MAX / 2);
+        assert_eq!(c.apply(2 * VALUE_MAX, &[]), VALUE_MAX);
+        let c = curve(&[0, 100]);
+        assert_eq!(c.apply(0, &[]), VALUE_MAX / 2);
+    }
+
+    #[test]
+    fn large_points_are_limited() {
+        let c = curve(&[-i16::MAX, i16::MAX]);
+        let limit = GLOBAL_LIMIT as Value * VALUE_MAX / 100;
+        assert_eq!(c.apply(VALUE_MAX, &[]), limit);
+        assert_eq!(c.apply(-VALUE_MAX, &[]), -limit);
+        let c = PointCurve {
+            points: [Param::Value(0), Param::Global(0)]
+                .iter()
+                .copied()
+                .collect(),
+        };
+        assert_eq!(c.apply(VALUE_MAX, &[i16::MAX]), limit);
+    }
+
+    #[test]
+    fn diff_reduces_one_side() {
+        let diff = Curve::Diff(Param::Value(50));
+        assert_eq!(diff.apply(-VALUE_MAX, &[], &[]), -VALUE_MAX / 2);
+        assert_eq!(diff.apply(VALUE_MAX, &[], &[]), VALUE_MAX);
+    }
+}
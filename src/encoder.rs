@@ -0,0 +1,149 @@
+//! Rotary encoders
+//!
+//! Decoding of the quadrature signals is done by [`Encoder`] from the pin levels after each edge
+//! (EXTI). What a detent does is decided by an [`EncoderOutput`].
+
+/// Quadrature decoder, counting detents
+#[derive(Debug)]
+pub struct Encoder {
+    /// Last levels of the A and B channel, as `0bAB`
+    state: u8,
+    /// Counts not yet taken as detents
+    counts: i32,
+    counts_per_detent: i32,
+}
+
+impl Encoder {
+    /// Valid transitions, indexed by `0bABab` with `ab` being the old state. Invalid transitions
+    /// (both channels changed, a bounce was missed) are ignored.
+    const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+    pub const fn new(counts_per_detent: u8) -> Self {
+        Self {
+            state: 0,
+            counts: 0,
+            counts_per_detent: counts_per_detent as i32,
+        }
+    }
+
+    /// Process the levels of both channels after an edge on either of them
+    pub fn update_pins(&mut self, a: bool, b: bool) {
+        let new = (a as u8) << 1 | b as u8;
+        self.counts += Self::TRANSITIONS[(new << 2 | self.state) as usize] as i32;
+        self.state = new;
+    }
+
+    /// Take the detents turned since the last call, clockwise is positive
+    pub fn take_detents(&mut self) -> i32 {
+        let detents = self.counts / self.counts_per_detent;
+        self.counts -= detents * self.counts_per_detent;
+        detents
+    }
+}
+
+/// What turning an encoder does
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EncoderMapping {
+    /// Virtual absolute axis replacing the report axis `axis`, moved by `step` per detent within
+    /// `min..=max`
+    Axis {
+        axis: usize,
+        step: i16,
+        min: i16,
+        max: i16,
+    },
+    /// Pulse on the HID button `up` or `down` for each detent
+    Buttons { up: usize, down: usize },
+    /// Trim steps on the given axis
+    Trim { axis: usize },
+    /// Adjust a global variable of the active flight mode by `step` per detent
+    Global { index: u8, step: i16 },
+}
+
+/// Mapping of the detents of one encoder
+#[derive(Debug)]
+pub struct EncoderOutput {
+    mapping: EncoderMapping,
+    /// Value of the virtual axis
+    axis: i16,
+    /// Detents not yet emitted as button pulses or trim steps
+    pending: i32,
+    /// Remaining frames of the current pulse (positive) or the pause after it (negative)
+    pulse: i8,
+    /// Button of the current pulse
+    button: Option<usize>,
+}
+
+impl EncoderOutput {
+    /// Frames a button is pressed per detent, and released after that
+    const PULSE_FRAMES: i8 = 20;
+
+    pub const fn new(mapping: EncoderMapping) -> Self {
+        Self {
+            mapping,
+            axis: 0,
+            pending: 0,
+            pulse: 0,
+            button: None,
+        }
+    }
+
+    /// Advance one frame, processing the detents turned since the last frame
+    pub fn update(&mut self, detents: i32) {
+        match self.mapping {
+            EncoderMapping::Axis { step, min, max, .. } => {
+                let v = self.axis as i32 + detents * step as i32;
+                self.axis = num::clamp(v, min as i32, max as i32) as i16;
+            }
+            EncoderMapping::Buttons { up, down } => {
+                self.pending += detents;
+                if self.pulse > 0 {
+                    self.pulse -= 1;
+                    if self.pulse == 0 {
+                        self.button = None;
+                        self.pulse = -Self::PULSE_FRAMES;
+                    }
+                } else if self.pulse < 0 {
+                    self.pulse += 1;
+                } else if self.pending != 0 {
+                    self.button = Some(if self.pending > 0 { up } else { down });
+                    self.pending -= self.pending.signum();
+                    self.pulse = Self::PULSE_FRAMES;
+                }
+            }
+            EncoderMapping::Trim { .. } | EncoderMapping::Global { .. } => self.pending += detents,
+        }
+    }
+
+    /// Report axis and value of the virtual axis as `(axis, value)`, if mapped to one
+    pub fn axis(&self) -> Option<(usize, i16)> {
+        match self.mapping {
+            EncoderMapping::Axis { axis, .. } => Some((axis, self.axis)),
+            _ => None,
+        }
+    }
+
+    /// Currently pressed HID button, if any
+    pub fn button(&self) -> Option<usize> {
+        self.button
+    }
+
+    /// Take the trim steps as `(axis, steps)`, if mapped to a trim
+    pub fn take_trim_steps(&mut self) -> Option<(usize, i32)> {
+        match self.mapping {
+            EncoderMapping::Trim { axis } => Some((axis, core::mem::take(&mut self.pending))),
+            _ => None,
+        }
+    }
+
+    /// Take the change of a global variable as `(index, delta)`, if mapped to one
+    pub fn take_global_delta(&mut self) -> Option<(usize, i32)> {
+        match self.mapping {
+            EncoderMapping::Global { index, step } => {
+                let detents = core::mem::take(&mut self.pending);
+                Some((index as usize, detents * step as i32))
+            }
+            _ => None,
+        }
+    }
+}
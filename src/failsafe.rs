@@ -0,0 +1,96 @@
+//! Failsafe of the outputs
+//!
+//! Once an input source stops delivering data, the outputs are replaced according to the failsafe
+//! mode of their channel until the source is back.
+use crate::mixer::CHANNELS;
+use crate::types::{Value, VALUE_MAX};
+
+/// What a channel outputs during failsafe
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum FailsafeMode {
+    /// The last output before the failsafe
+    #[default]
+    Hold,
+    /// Fixed output in tenths of a percent
+    Custom(i16),
+    /// Nothing, outputs which can not omit single channels stop sending altogether
+    NoPulses,
+}
+
+/// Detects the loss of an input source which delivers data regularly
+#[derive(Debug)]
+pub struct Watchdog {
+    /// Time without data after which the source is lost, in milliseconds
+    timeout: u32,
+    last: Option<u32>,
+}
+
+impl Watchdog {
+    pub const fn new(timeout: u32) -> Self {
+        Self {
+            timeout,
+            last: None,
+        }
+    }
+
+    /// Data arrived, `now` is in milliseconds
+    pub fn feed(&mut self, now: u32) {
+        self.last = Some(now);
+    }
+
+    /// Whether no data arrived within the timeout, which is also the case before the first data
+    pub fn is_lost(&self, now: u32) -> bool {
+        self.last
+            .is_none_or(|last| now.wrapping_sub(last) > self.timeout)
+    }
+}
+
+#[derive(Debug)]
+pub struct Failsafe {
+    active: bool,
+    /// Outputs of the last frame before the failsafe
+    hold: [Value; CHANNELS],
+}
+
+impl Default for Failsafe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Failsafe {
+    pub const fn new() -> Self {
+        Self {
+            active: false,
+            hold: [0; CHANNELS],
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Replace the outputs if an input source is `lost`, `None` for channels without pulses
+    pub fn apply(
+        &mut self,
+        modes: &[FailsafeMode; CHANNELS],
+        lost: bool,
+        outputs: &[Value; CHANNELS],
+    ) -> [Option<Value>; CHANNELS] {
+        self.active = lost;
+        if !lost {
+            self.hold = *outputs;
+            return outputs.map(Some);
+        }
+
+        let mut failsafe = [None; CHANNELS];
+        for ((output, mode), hold) in failsafe.iter_mut().zip(modes.iter()).zip(self.hold.iter()) {
+            *output = match *mode {
+                FailsafeMode::Hold => Some(*hold),
+                FailsafeMode::Custom(permille) => Some(permille as Value * VALUE_MAX / 1000),
+                FailsafeMode::NoPulses => None,
+            };
+        }
+        failsafe
+    }
+}
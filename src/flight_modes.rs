@@ -0,0 +1,185 @@
+//! Flight modes
+//!
+//! Each flight mode has its own trims and mix lines can be restricted to some of them. Switching
+//! modes fades the channels from the old to the new mode, the mixer is evaluated for every mode
+//! which is still fading and the results are blended by their fade weight. The delayed and slowed
+//! mix lines therefore follow their values separately in every mode.
+use heapless::String;
+
+use crate::mixer::{Condition, Inputs, CHANNELS};
+use crate::types::Value;
+
+/// Number of flight modes, mode `0` is active if no other one is selected
+pub const FLIGHT_MODES: usize = 9;
+/// Longest name of a flight mode
+pub const NAME_LEN: usize = 12;
+
+/// Fade weight of a fully active mode
+const FADE_MAX: u32 = 1 << 16;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlightMode {
+    pub name: String<NAME_LEN>,
+    /// Selects the mode, the first mode with a fulfilled condition wins, unused for mode `0`
+    pub condition: Option<Condition>,
+    /// Time to fade in once selected, in tenths of a second
+    pub fade_in: u16,
+    /// Time to fade out once deselected, in tenths of a second
+    pub fade_out: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct FlightModes {
+    pub modes: [FlightMode; FLIGHT_MODES],
+    active: usize,
+    /// Fade weight of every mode, from `0` to `FADE_MAX`
+    weights: [u32; FLIGHT_MODES],
+    last_update: u32,
+}
+
+impl Default for FlightModes {
+    fn default() -> Self {
+        let mut modes: [FlightMode; FLIGHT_MODES] = Default::default();
+        modes[0].name.push_str("Normal").ok();
+        let mut weights = [0; FLIGHT_MODES];
+        weights[0] = FADE_MAX;
+        Self {
+            modes,
+            active: 0,
+            weights,
+            last_update: 0,
+        }
+    }
+}
+
+/// Weight change over `elapsed` milliseconds for a fade time in tenths of a second
+fn fade_step(fade: u16, elapsed: u32) -> u32 {
+    if fade == 0 {
+        FADE_MAX
+    } else {
+        (FADE_MAX as u64 * elapsed as u64 / (fade as u64 * 100)).max(1) as u32
+    }
+}
+
+impl FlightModes {
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Name of the active mode
+    pub fn name(&self) -> &str {
+        &self.modes[self.active].name
+    }
+
+    /// Select the active mode and advance the fades, `now` is in milliseconds
+    ///
+    /// Returns whether the active mode changed.
+    pub fn update(&mut self, inputs: &Inputs, now: u32) -> bool {
+        let elapsed = now.wrapping_sub(self.last_update);
+        self.last_update = now;
+
+        let active = self
+            .modes
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(_, mode)| mode.condition.is_some_and(|c| inputs.check(c)))
+            .map_or(0, |(i, _)| i);
+        let changed = active != self.active;
+        self.active = active;
+
+        for (i, (mode, weight)) in self.modes.iter().zip(self.weights.iter_mut()).enumerate() {
+            *weight = if i == active {
+                (*weight + fade_step(mode.fade_in, elapsed)).min(FADE_MAX)
+            } else {
+                weight.saturating_sub(fade_step(mode.fade_out, elapsed))
+            };
+        }
+        changed
+    }
+
+    /// Blend the channels of all fading modes, `evaluate` computes the channels of a mode
+    pub fn blend(&self, mut evaluate: impl FnMut(usize) -> [Value; CHANNELS]) -> [Value; CHANNELS] {
+        let fading = self
+            .weights
+            .iter()
+            .enumerate()
+            .filter(|(i, weight)| *i != self.active && **weight > 0)
+            .count();
+        if fading == 0 {
+            return evaluate(self.active);
+        }
+
+        let mut sums = [0i64; CHANNELS];
+        let mut total = 0i64;
+        for (mode, weight) in self.weights.iter().enumerate() {
+            if *weight == 0 {
+                continue;
+            }
+            for (sum, channel) in sums.iter_mut().zip(evaluate(mode).iter()) {
+                *sum += *channel as i64 * *weight as i64;
+            }
+            total += *weight as i64;
+        }
+        sums.map(|sum| (sum / total) as Value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::globals::Param;
+    use crate::mixer::{MixLine, Mixer, Source, Switch, MAX_MIXES};
+    use crate::slow::{Follower, Times};
+    use crate::types::VALUE_MAX;
+
+    #[test]
+    fn slowed_lines_fade_per_mode() {
+        let mut modes = FlightModes::default();
+        modes.modes[1].condition = Some(Condition::On(Switch::Input(0)));
+        modes.modes[1].fade_in = 10;
+        modes.modes[1].fade_out = 10;
+        modes.modes[0].fade_out = 10;
+        // the first global variable reverses the line in the second mode
+        let mut mixer = Mixer::default();
+        let mut mix = MixLine::new(0, Source::Axis(0));
+        mix.weight = Param::Global(0);
+        mix.slow = Times { up: 20, down: 20 };
+        mixer.add(mix).unwrap();
+        let mut followers = [[Follower::new(); MAX_MIXES]; FLIGHT_MODES];
+
+        let mut frame = |modes: &mut FlightModes, switch: bool, now: u32| {
+            let switches = [switch];
+            let inputs = Inputs {
+                axes: &[VALUE_MAX],
+                trims: &[],
+                inputs: &switches,
+                logical: &[],
+                gestures: &[],
+                channels: &[],
+                flight_mode: 0,
+                globals: &[],
+                now,
+                heli: &[],
+            };
+            modes.update(&inputs, now);
+            modes.blend(|mode| {
+                let globals = [if mode == 0 { 100 } else { -100 }];
+                let inputs = Inputs {
+                    flight_mode: mode as u8,
+                    globals: &globals,
+                    ..inputs
+                };
+                let mut channels = [0; CHANNELS];
+                mixer.evaluate(&inputs, &mut followers[mode], &mut channels);
+                channels
+            })[0]
+        };
+
+        assert_eq!(frame(&mut modes, false, 0), VALUE_MAX);
+        assert_eq!(frame(&mut modes, true, 500), 0);
+        assert_eq!(frame(&mut modes, true, 1000), -VALUE_MAX);
+        // back in the first mode, which starts where it was left
+        assert_eq!(frame(&mut modes, false, 2000), VALUE_MAX);
+    }
+}
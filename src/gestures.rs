@@ -0,0 +1,180 @@
+//! Virtual buttons from gestures on the digital inputs
+//!
+//! The inputs are debounced, then each gesture turns a way of pressing one of them into a switch
+//! of its own, see [`Switch::Gesture`](crate::mixer::Switch::Gesture). Short and double presses
+//! turn their switch on for a moment, a long press keeps it on while the input is held and a
+//! toggle latches it until the next press. A short press on an input with a double press waits
+//! whether a second press follows.
+use crate::config::DIGITAL_PINS;
+
+/// Number of gestures of the device
+pub const GESTURES: usize = 8;
+/// How long an input has to be stable to change, in milliseconds
+const DEBOUNCE: u32 = 20;
+/// Shortest long press
+const LONG_PRESS: u32 = 500;
+/// Longest time between the presses of a double press
+const DOUBLE_PRESS: u32 = 300;
+/// How long short and double presses turn their switch on
+const PULSE: u32 = 100;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Kind {
+    Short,
+    Long,
+    Double,
+    /// On and off with every press
+    Toggle,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Gesture {
+    /// Digital input
+    pub pin: u8,
+    pub kind: Kind,
+}
+
+/// What happened to an input in a frame
+#[derive(Copy, Clone, Debug, Default)]
+struct Events {
+    pressed: bool,
+    short: bool,
+    long: bool,
+    double: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Button {
+    /// Debounced state
+    pressed: bool,
+    /// Since when the input differs from the debounced state
+    changing_since: Option<u32>,
+    pressed_at: u32,
+    released_at: u32,
+    /// A short press waiting to become the first of a double press
+    pending_short: bool,
+    /// The press is the second of a double press
+    second: bool,
+}
+
+impl Button {
+    const fn new() -> Self {
+        Self {
+            pressed: false,
+            changing_since: None,
+            pressed_at: 0,
+            released_at: 0,
+            pending_short: false,
+            second: false,
+        }
+    }
+
+    /// Debounce the input and detect the presses, short presses wait if `double` is used
+    fn update(&mut self, input: bool, double: bool, now: u32) -> Events {
+        let mut events = Events::default();
+        if input == self.pressed {
+            self.changing_since = None;
+        } else {
+            let since = *self.changing_since.get_or_insert(now);
+            if now.wrapping_sub(since) >= DEBOUNCE {
+                self.pressed = input;
+                self.changing_since = None;
+                if input {
+                    events.pressed = true;
+                    self.second = self.pending_short;
+                    self.pending_short = false;
+                    self.pressed_at = now;
+                } else {
+                    let short = now.wrapping_sub(self.pressed_at) < LONG_PRESS;
+                    if short && self.second {
+                        events.double = true;
+                    } else if short && double {
+                        self.pending_short = true;
+                    } else if short {
+                        events.short = true;
+                    }
+                    self.second = false;
+                    self.released_at = now;
+                }
+            }
+        }
+        if self.pending_short && now.wrapping_sub(self.released_at) > DOUBLE_PRESS {
+            self.pending_short = false;
+            events.short = true;
+        }
+        events.long = self.pressed && now.wrapping_sub(self.pressed_at) >= LONG_PRESS;
+        events
+    }
+}
+
+pub struct Gestures {
+    buttons: [Button; DIGITAL_PINS],
+    on: [bool; GESTURES],
+    /// End of the pulses of short and double presses
+    until: [u32; GESTURES],
+}
+
+impl Default for Gestures {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gestures {
+    pub const fn new() -> Self {
+        Self {
+            buttons: [Button::new(); DIGITAL_PINS],
+            on: [false; GESTURES],
+            until: [0; GESTURES],
+        }
+    }
+
+    /// Evaluate the gestures on the inputs, `now` is in milliseconds
+    pub fn update(
+        &mut self,
+        config: &[Option<Gesture>; GESTURES],
+        inputs: &[bool],
+        now: u32,
+    ) -> [bool; GESTURES] {
+        let mut events = [Events::default(); DIGITAL_PINS];
+        for (pin, (button, events)) in self.buttons.iter_mut().zip(events.iter_mut()).enumerate() {
+            let double = config
+                .iter()
+                .flatten()
+                .any(|g| g.pin as usize == pin && g.kind == Kind::Double);
+            let input = inputs.get(pin).copied().unwrap_or(false);
+            *events = button.update(input, double, now);
+        }
+
+        for (i, gesture) in config.iter().enumerate() {
+            let gesture = match gesture {
+                Some(gesture) => gesture,
+                None => {
+                    self.on[i] = false;
+                    continue;
+                }
+            };
+            let events = events
+                .get(gesture.pin as usize)
+                .copied()
+                .unwrap_or_default();
+            let pulse = match gesture.kind {
+                Kind::Short => events.short,
+                Kind::Double => events.double,
+                Kind::Long => {
+                    self.on[i] = events.long;
+                    continue;
+                }
+                Kind::Toggle => {
+                    self.on[i] ^= events.pressed;
+                    continue;
+                }
+            };
+            if pulse {
+                self.until[i] = now.wrapping_add(PULSE);
+            }
+            self.on[i] = (self.until[i].wrapping_sub(now) as i32) > 0;
+        }
+        self.on
+    }
+}
@@ -0,0 +1,113 @@
+//! Global variables
+//!
+//! A few values per model and flight mode which numeric parameters of mix lines and curves can
+//! refer to, so rates can be tuned in one place, e.g. by a trim switch or an encoder.
+use crate::flight_modes::FLIGHT_MODES;
+
+/// Number of global variables of a model
+pub const GLOBALS: usize = 6;
+/// Largest magnitude of a global variable, in percent
+pub const GLOBAL_LIMIT: i16 = 500;
+
+/// Encoding of the first reference to a global variable, smaller magnitudes are fixed values
+const REFERENCE: i16 = 0x7f00;
+
+/// Numeric parameter, fixed or referring to a global variable
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Param {
+    Value(i16),
+    Global(u8),
+    /// Negated global variable
+    Negated(u8),
+}
+
+impl Param {
+    /// Value with `globals` being the values of the active flight mode
+    pub fn get(self, globals: &[i16]) -> i16 {
+        let global = |i: u8| globals.get(i as usize).copied().unwrap_or(0);
+        match self {
+            Self::Value(v) => v,
+            Self::Global(i) => global(i),
+            Self::Negated(i) => global(i).saturating_neg(),
+        }
+    }
+
+    /// Single `i16` encoding, references are outside the range of sensible values
+    pub fn to_raw(self) -> i16 {
+        match self {
+            Self::Value(v) => num::clamp(v, 1 - REFERENCE, REFERENCE - 1),
+            Self::Global(i) => REFERENCE + i as i16,
+            Self::Negated(i) => -REFERENCE - i as i16,
+        }
+    }
+
+    /// Parameter from [`Param::to_raw`], `None` for references to unknown variables
+    pub fn from_raw(raw: i16) -> Option<Self> {
+        let index = |offset: i16| ((offset as usize) < GLOBALS).then_some(offset as u8);
+        match raw {
+            r if r >= REFERENCE => index(r - REFERENCE).map(Self::Global),
+            r if r <= -REFERENCE => index(-REFERENCE - r).map(Self::Negated),
+            v => Some(Self::Value(v)),
+        }
+    }
+}
+
+impl From<i16> for Param {
+    fn from(v: i16) -> Self {
+        Self::Value(v)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Globals {
+    /// Values of every flight mode, in percent
+    pub values: [[i16; GLOBALS]; FLIGHT_MODES],
+    /// Whether a variable has its own value in each flight mode, all modes share the value of
+    /// mode `0` otherwise
+    pub per_mode: [bool; GLOBALS],
+}
+
+impl Globals {
+    fn mode(&self, mode: usize, index: usize) -> usize {
+        if self.per_mode[index] {
+            mode
+        } else {
+            0
+        }
+    }
+
+    /// Values of a flight mode
+    pub fn get(&self, mode: usize) -> [i16; GLOBALS] {
+        let mut values = [0; GLOBALS];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = self.values[self.mode(mode, i)][i];
+        }
+        values
+    }
+
+    /// Set a variable as seen by a flight mode, returns whether it changed
+    pub fn set(&mut self, mode: usize, index: usize, value: i16) -> bool {
+        if mode >= FLIGHT_MODES || index >= GLOBALS {
+            return false;
+        }
+        let mode = self.mode(mode, index);
+        let value = num::clamp(value, -GLOBAL_LIMIT, GLOBAL_LIMIT);
+        let old = core::mem::replace(&mut self.values[mode][index], value);
+        old != value
+    }
+
+    /// Move a variable as seen by a flight mode by `delta`, returns whether it changed
+    pub fn adjust(&mut self, mode: usize, index: usize, delta: i32) -> bool {
+        match self.get(mode).get(index) {
+            Some(value) => {
+                let value = num::clamp(
+                    *value as i32 + delta,
+                    -(GLOBAL_LIMIT as i32),
+                    GLOBAL_LIMIT as i32,
+                );
+                self.set(mode, index, value as i16)
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Helicopter swashplate mixing (CCPM)
+//!
+//! The cyclic and collective inputs are combined into the positions of the swashplate servos,
+//! which mix lines refer to as [`Source::Heli`], together with the output of the throttle curve.
+use crate::curves::{Curve, PointCurve};
+use crate::flight_modes::FLIGHT_MODES;
+use crate::mixer::{Inputs, Source};
+use crate::types::{Value, VALUE_MAX};
+
+/// The three swashplate servos followed by the throttle
+pub const HELI_OUTPUTS: usize = 4;
+
+/// Arrangement of the swashplate servos
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Swash {
+    /// One servo per function, mixed mechanically: aileron, elevator, pitch
+    H1,
+    /// Elevator servo and two aileron servos at 120°
+    H120,
+    /// Elevator servo and two aileron servos at 140°, with equal cyclic throw
+    H140,
+    /// Elevator servo and two aileron servos at 90°
+    H90,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Heli {
+    /// `None` disables the swashplate mixing
+    pub swash: Option<Swash>,
+    pub aileron: Source,
+    pub elevator: Source,
+    pub collective: Source,
+    pub throttle: Source,
+    /// Weights in percent, negative values reverse the function
+    pub aileron_weight: i16,
+    pub elevator_weight: i16,
+    pub collective_weight: i16,
+    /// Largest cyclic deflection in percent, `0` does not limit it
+    pub ring: u8,
+    /// Pitch curve of every flight mode, applied to the collective
+    pub pitch_curves: [Curve; FLIGHT_MODES],
+    /// Throttle curve of every flight mode
+    pub throttle_curves: [Curve; FLIGHT_MODES],
+}
+
+impl Default for Heli {
+    fn default() -> Self {
+        Self {
+            swash: None,
+            aileron: Source::Axis(0),
+            elevator: Source::Axis(1),
+            collective: Source::Axis(2),
+            throttle: Source::Axis(2),
+            aileron_weight: 100,
+            elevator_weight: 100,
+            collective_weight: 100,
+            ring: 0,
+            pitch_curves: [Curve::None; FLIGHT_MODES],
+            throttle_curves: [Curve::None; FLIGHT_MODES],
+        }
+    }
+}
+
+impl Heli {
+    /// Servo positions and throttle of a flight mode, all `0` if disabled
+    pub fn evaluate(&self, inputs: &Inputs, curves: &[PointCurve]) -> [Value; HELI_OUTPUTS] {
+        let swash = match self.swash {
+            Some(swash) => swash,
+            None => return [0; HELI_OUTPUTS],
+        };
+        let mode = (inputs.flight_mode as usize).min(FLIGHT_MODES - 1);
+        let weighted = |source, weight: i16| inputs.value(source) * weight as Value / 100;
+        let (mut a, mut e) = (
+            weighted(self.aileron, self.aileron_weight),
+            weighted(self.elevator, self.elevator_weight),
+        );
+        let curve =
+            |curve: Curve, source| curve.apply(inputs.value(source), curves, inputs.globals);
+        let c =
+            curve(self.pitch_curves[mode], self.collective) * self.collective_weight as Value / 100;
+        let throttle = curve(self.throttle_curves[mode], self.throttle);
+
+        // keep the cyclic within a circle
+        if self.ring > 0 {
+            let ring = self.ring as i64 * VALUE_MAX as i64 / 100;
+            let length = (a as i64 * a as i64 + e as i64 * e as i64).isqrt();
+            if length > ring {
+                a = (a as i64 * ring / length) as Value;
+                e = (e as i64 * ring / length) as Value;
+            }
+        }
+
+        let [s1, s2, s3] = match swash {
+            Swash::H1 => [a, e, c],
+            Swash::H120 => {
+                let a = a * 866 / 1000;
+                [c + e, c - e / 2 + a, c - e / 2 - a]
+            }
+            Swash::H140 => [c + e, c - e + a, c - e - a],
+            Swash::H90 => [c + e, c + a, c - a],
+        };
+        [s1, s2, s3, throttle]
+    }
+}
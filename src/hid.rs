@@ -2,63 +2,372 @@
 use usb_device::class_prelude::*;
 use usb_device::Result;
 
-const REPORT_DESCR: &[u8] = &[
-    0x05, 0x01, // USAGE_PAGE (Generic Desktop)
-    0x09, 0x04, // USAGE (Joystick)
-    0xA1, 0x01, // COLLECTION (Application)
-    //Axes SECTION
-    0x09, 0x01, //     USAGE (Pointer)
-    0xA1, 0x00, //     COLLECTION (Physical)
-    0x05, 0x01, //       USAGE_PAGE (Generic Desktop)
-    0x09, 0x30, //       USAGE (X)
-    0x09, 0x31, //       USAGE (Y)
-    0x09, 0x32, //       USAGE (Z)
-    0x09, 0x33, //       USAGE (Rx)
-    0x16, 0x0C, 0xFE, // LOGICAL_MINIMUM (-500)
-    0x26, 0xF4, 0x01, // LOGICAL_MAXIMUM (+500)
-    0x75, 0x10, //       REPORT_SIZE (16)
-    0x95, 0x04, //       REPORT_COUNT (4)
-    0x81, 0x02, //       INPUT (Data,Var,Abs)
-    0xC0, //     END_COLLECTION
-    //Dials SECTION
-    0x09, 0x36, //     USAGE (Dial)
-    0x09, 0x37, //     USAGE (Dial)
-    0x16, 0x0C, 0xFE, //LOGICAL_MINIMUM (-500)
-    0x26, 0xF4, 0x01, //LOGICAL_MAXIMUM (+500)
-    0x75, 0x10, //     REPORT_SIZE (16)
-    0x95, 0x02, //     REPORT_COUNT (2)
-    0x81, 0x02, //     INPUT (Data,Var,Abs)
-    //BUTTON SECTION
-    0x05, 0x09, //    USAGE_PAGE (Button)
-    0x19, 0x01, //    USAGE_MINIMUM (Button 1)
-    0x29, 0x06, //    USAGE_MAXIMUM (Button 6)
-    0x15, 0x00, //    LOGICAL_MINIMUM (0)
-    0x25, 0x01, //    LOGICAL_MAXIMUM (1)
-    0x75, 0x01, //    REPORT_SIZE (1)
-    0x95, 0x06, //    REPORT_COUNT (6)
-    0x81, 0x02, //    INPUT (Data,Var,Abs)
-    //PADDING
-    0x95, 0x01, //    REPORT_COUNT (1)
-    0x75, 0x02, //    REPORT_SIZE (2)
-    0x81, 0x03, //    INPUT (Cnst,Var,Abs)
-    0xC0, // END_COLLECTION
+use crate::codec::{Decode, Encode, Reader, Writer};
+use crate::config::{self, Config, ANALOG_PINS, CONFIG_SIZE};
+use crate::inputs::LinearInput;
+use crate::models::{Model, Request, MODEL_SIZE};
+use crate::report::{
+    Descriptor, Layout, ANALOG_REPORT_ID, ANALOG_REPORT_LEN, CALIBRATION_REPORT_ID,
+    CALIBRATION_REPORT_LEN, CONFIG_CHUNK, CONFIG_REPORT_ID, GLOBAL_REPORT_ID, INPUT_REPORT_ID,
+    MAX_REPORT, MODEL_CHUNK, MODEL_DATA_REPORT_ID, MODEL_DATA_REPORT_LEN, MODEL_REPORT_ID,
+    STATUS_REPORT_ID, STATUS_REPORT_LEN, TIMER_REPORT_ID, TIMER_REPORT_LEN, VERSION_REPORT_ID,
+};
+use crate::templates::Template;
+use crate::timers::{Timers, TIMERS};
+use crate::types::JoystickState;
+
+// descriptor types
+const HID_DESCRIPTOR: u8 = 0x21;
+const REPORT_DESCRIPTOR: u8 = 0x22;
+
+// class requests
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0A;
+const SET_PROTOCOL: u8 = 0x0B;
+
+// report types of GET_REPORT and SET_REPORT
+const INPUT_REPORT: u8 = 0x01;
+const OUTPUT_REPORT: u8 = 0x02;
+const FEATURE_REPORT: u8 = 0x03;
+
+const REPORT_PROTOCOL: u8 = 1;
+/// Unit of the idle rate in milliseconds
+const IDLE_UNIT: u32 = 4;
+
+/// Firmware version of the version feature report
+const FIRMWARE_VERSION: [u8; 3] = [
+    version_part(env!("CARGO_PKG_VERSION_MAJOR")),
+    version_part(env!("CARGO_PKG_VERSION_MINOR")),
+    version_part(env!("CARGO_PKG_VERSION_PATCH")),
 ];
 
+const fn version_part(s: &str) -> u8 {
+    let (s, mut v, mut i) = (s.as_bytes(), 0, 0);
+    while i < s.len() {
+        v = v * 10 + (s[i] - b'0');
+        i += 1;
+    }
+    v
+}
+
 pub struct HIDClass<'a, B: UsbBus> {
     report_if: InterfaceNumber,
     report_ep: EndpointIn<'a, B>,
+    /// Layout the device enumerated with
+    layout: Layout,
+    descriptor: Descriptor,
+    /// The layout of the active model differs, takes effect once the device enumerates again
+    layout_changed: bool,
+    /// Latest input report
+    report: [u8; MAX_REPORT],
+    report_len: usize,
+    /// The latest report is not sent yet
+    report_pending: bool,
+    /// Milliseconds since a report was sent
+    since_report: u32,
+    /// Idle rate in 4 ms, `0` only sends reports which changed
+    idle: u8,
+    model_request: Option<Request>,
+    /// Global variable to set as `(flight mode, index, value)`
+    global_request: Option<(u8, u8, i16)>,
+    timer_report: [u8; TIMER_REPORT_LEN],
+    analog_report: [u8; ANALOG_REPORT_LEN],
+    calibration_report: [u8; CALIBRATION_REPORT_LEN],
+    status_report: [u8; STATUS_REPORT_LEN],
+    /// Configuration written by the host, applied once it is complete
+    config: [u8; CONFIG_SIZE],
+    config_request: Option<Config>,
+    /// Encoded active model read by the host, or the one it writes
+    model: [u8; MODEL_SIZE],
+    model_len: usize,
+    /// Offset of the chunk read by the host
+    model_offset: usize,
+    /// The active model is encoded for reading
+    model_ready: bool,
+    /// The host sends the chunks of a model
+    model_writing: bool,
+    /// The host wants to read the active model
+    model_read_request: bool,
+    /// The host wrote a model of the current settings version
+    model_written: bool,
 }
 
 impl<B: UsbBus> HIDClass<'_, B> {
-    pub fn new(alloc: &UsbBusAllocator<B>) -> HIDClass<'_, B> {
+    pub fn new(alloc: &UsbBusAllocator<B>, layout: Layout) -> HIDClass<'_, B> {
+        let descriptor = layout.descriptor();
+        debug_assert_eq!(descriptor.report_bits() + 8, 8 * layout.report_len());
+        let mut report = [0; MAX_REPORT];
+        let report_len = layout.write(&JoystickState::default(), &mut report);
         HIDClass {
             report_if: alloc.interface(),
-            report_ep: alloc.interrupt(13, 1),
+            report_ep: alloc.interrupt(layout.report_len() as u16, 1),
+            layout,
+            descriptor,
+            layout_changed: false,
+            report,
+            report_len,
+            report_pending: true,
+            since_report: 0,
+            idle: 0,
+            model_request: None,
+            global_request: None,
+            timer_report: [0; TIMER_REPORT_LEN],
+            analog_report: [0; ANALOG_REPORT_LEN],
+            calibration_report: [0; CALIBRATION_REPORT_LEN],
+            status_report: [0; STATUS_REPORT_LEN],
+            config: [0; CONFIG_SIZE],
+            config_request: None,
+            model: [0; MODEL_SIZE],
+            model_len: 0,
+            model_offset: 0,
+            model_ready: false,
+            model_writing: false,
+            model_read_request: false,
+            model_written: false,
+        }
+    }
+
+    /// Send the state if it changed or the idle rate is due, `elapsed` is in milliseconds since
+    /// the previous call
+    pub fn write(&mut self, state: &JoystickState, elapsed: u32) {
+        let mut report = [0; MAX_REPORT];
+        let len = self.layout.write(state, &mut report);
+        if report[..len] != self.report[..self.report_len] {
+            self.report = report;
+            self.report_len = len;
+            self.report_pending = true;
         }
+
+        self.since_report = self.since_report.saturating_add(elapsed);
+        let idle = self.idle as u32 * IDLE_UNIT;
+        let due = self.report_pending || (idle > 0 && self.since_report >= idle);
+        if due && self.report_ep.write(&self.report[..len]).is_ok() {
+            self.report_pending = false;
+            self.since_report = 0;
+        }
+    }
+
+    /// Class specific descriptor of the interface, without its length and type
+    fn hid_descriptor(&self) -> [u8; 7] {
+        let [lo, hi] = (self.descriptor.as_bytes().len() as u16).to_le_bytes();
+        [
+            0x01, // bcdHID
+            0x01, // bcdHID
+            0x00, // bCountryCode
+            0x01, // bNumDescriptors
+            REPORT_DESCRIPTOR,
+            lo, // wDescriptorLength
+            hi, // wDescriptorLength
+        ]
+    }
+
+    /// Layout of the active model, returns whether it differs from the enumerated one for the
+    /// first time
+    pub fn set_layout(&mut self, layout: Layout) -> bool {
+        let changed = layout != self.layout;
+        let first = changed && !self.layout_changed;
+        self.layout_changed = changed;
+        first
+    }
+
+    /// Whether the device has to enumerate again for the layout of the active model
+    pub fn layout_changed(&self) -> bool {
+        self.layout_changed
+    }
+
+    /// Model memory operation requested by the host
+    pub fn take_model_request(&mut self) -> Option<Request> {
+        self.model_request.take()
+    }
+
+    /// Global variable set by the host as `(flight mode, index, value)`
+    pub fn take_global_request(&mut self) -> Option<(u8, u8, i16)> {
+        self.global_request.take()
+    }
+
+    /// Device configuration written by the host
+    pub fn take_config_request(&mut self) -> Option<Config> {
+        self.config_request.take()
+    }
+
+    /// Whether the host wants to read the active model, which has to be passed to
+    /// [`HIDClass::set_model`]
+    pub fn take_model_read_request(&mut self) -> bool {
+        core::mem::take(&mut self.model_read_request)
     }
 
-    pub fn write(&mut self, data: &[u8]) {
-        self.report_ep.write(data).ok();
+    /// Active model read by the host, ignored while the host writes one
+    pub fn set_model(&mut self, model: &Model) {
+        if self.model_writing || self.model_written {
+            return;
+        }
+        let mut w = Writer::new(&mut self.model);
+        model.encode(&mut w);
+        // the model size is bounded, it always fits
+        self.model_len = w.finish().unwrap_or(0);
+        self.model_ready = true;
+    }
+
+    /// Encoding of a model written by the host, of the current settings version
+    pub fn take_model_data(&mut self) -> Option<&[u8]> {
+        if !core::mem::take(&mut self.model_written) {
+            return None;
+        }
+        // it is read again once it is applied
+        self.model_read_request = true;
+        Some(&self.model[..self.model_len])
+    }
+
+    /// Update the timers reported to the host
+    pub fn set_timers(&mut self, timers: &Timers) {
+        self.timer_report[0] = TIMER_REPORT_ID;
+        self.timer_report[1] =
+            (0..TIMERS).fold(0, |acc, i| acc | (timers.is_running(i) as u8) << i);
+        for (i, bytes) in self.timer_report[2..].chunks_mut(4).enumerate() {
+            bytes.copy_from_slice(&timers.value(i).to_le_bytes());
+        }
+    }
+
+    /// Update the raw readings and the calibration of the analog inputs reported to the host
+    pub fn set_analog(&mut self, readings: &[u16; ANALOG_PINS], calibration: &[LinearInput]) {
+        self.analog_report[0] = ANALOG_REPORT_ID;
+        for (bytes, reading) in self.analog_report[1..].chunks_mut(2).zip(readings.iter()) {
+            bytes.copy_from_slice(&reading.to_le_bytes());
+        }
+        self.calibration_report[0] = CALIBRATION_REPORT_ID;
+        for (bytes, input) in self.calibration_report[1..]
+            .chunks_mut(7)
+            .zip(calibration.iter())
+        {
+            let (state, start, mid, end) = match *input {
+                LinearInput::NoCalibration => (0, 0, 0, 0),
+                LinearInput::OngoingCalibration { start, end } => (1, start, 0, end),
+                LinearInput::Calibrated { start, mid, end } => (2, start, mid, end),
+            };
+            bytes[0] = state;
+            bytes[1..3].copy_from_slice(&start.to_le_bytes());
+            bytes[3..5].copy_from_slice(&mid.to_le_bytes());
+            bytes[5..7].copy_from_slice(&end.to_le_bytes());
+        }
+    }
+
+    /// Update the status reported to the host, `status` are the bits of the input report and
+    /// `failed_inputs` one bit per analog input
+    pub fn set_status(
+        &mut self,
+        status: u8,
+        model: u8,
+        flight_mode: u8,
+        throttle: u8,
+        failed_inputs: u8,
+    ) {
+        self.status_report = [
+            STATUS_REPORT_ID,
+            status,
+            model,
+            flight_mode,
+            throttle,
+            self.layout_changed as u8,
+            failed_inputs,
+        ];
+    }
+
+    /// Handle a chunk or the end of a model written by the host or the selection of a chunk to
+    /// read, returns whether it is valid
+    fn write_model(&mut self, data: &[u8]) -> bool {
+        match *data {
+            [_, 0, lo, hi, ref chunk @ ..] => {
+                let offset = u16::from_le_bytes([lo, hi]) as usize;
+                // the padding of the last chunk may reach past the end
+                match self.model.get_mut(offset..) {
+                    Some(buf) if !buf.is_empty() && !self.model_written => {
+                        let len = chunk.len().min(MODEL_CHUNK).min(buf.len());
+                        buf[..len].copy_from_slice(&chunk[..len]);
+                        self.model_writing = true;
+                        self.model_ready = false;
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            // only models of the current settings version, older ones are not migrated
+            [_, 1, lo, hi, version, ..] if self.model_writing && version == config::VERSION => {
+                let len = u16::from_le_bytes([lo, hi]) as usize;
+                if len > MODEL_SIZE {
+                    return false;
+                }
+                self.model_len = len;
+                self.model_writing = false;
+                self.model_written = true;
+                true
+            }
+            // the latest model, a partly written one is dropped
+            [_, 2, ..] if !self.model_written => {
+                self.model_writing = false;
+                self.model_ready = false;
+                self.model_read_request = true;
+                self.model_offset = 0;
+                true
+            }
+            [_, 3, lo, hi, ..] => {
+                self.model_offset = u16::from_le_bytes([lo, hi]) as usize;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Chunk of the model read by the host
+    fn model_report(&self) -> [u8; MODEL_DATA_REPORT_LEN] {
+        let mut report = [0; MODEL_DATA_REPORT_LEN];
+        report[0] = MODEL_DATA_REPORT_ID;
+        if self.model_ready {
+            report[1] = config::VERSION;
+            report[2..4].copy_from_slice(&(self.model_len as u16).to_le_bytes());
+            let data = self.model[..self.model_len]
+                .get(self.model_offset..)
+                .unwrap_or(&[]);
+            let len = data.len().min(MODEL_CHUNK);
+            report[4..4 + len].copy_from_slice(&data[..len]);
+        }
+        report
+    }
+
+    /// Handle a chunk or the end of the configuration written by the host, returns whether it is
+    /// valid
+    fn write_config(&mut self, data: &[u8]) -> bool {
+        match *data {
+            [_, 0, lo, hi, ref chunk @ ..] => {
+                let offset = u16::from_le_bytes([lo, hi]) as usize;
+                // the padding of the last chunk may reach past the end
+                match self.config.get_mut(offset..) {
+                    Some(buf) if !buf.is_empty() => {
+                        let len = chunk.len().min(CONFIG_CHUNK).min(buf.len());
+                        buf[..len].copy_from_slice(&chunk[..len]);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            [_, 1, lo, hi, ..] => {
+                let len = u16::from_le_bytes([lo, hi]) as usize;
+                let mut r = match self.config.get(..len) {
+                    Some(data) => Reader::new(data),
+                    None => return false,
+                };
+                match Config::decode(&mut r) {
+                    Ok(config) if r.is_done() => {
+                        self.config_request = Some(config);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
     }
 }
 
@@ -71,19 +380,7 @@ impl<B: UsbBus> UsbClass<B> for HIDClass<'_, B> {
             0x05, //USB_INTERFACE_GAMEPAD
         )?;
 
-        let descr_len: u16 = REPORT_DESCR.len() as u16;
-        writer.write(
-            0x21,
-            &[
-                0x01,                   // bcdHID
-                0x01,                   // bcdHID
-                0x00,                   // bCountryCode
-                0x01,                   // bNumDescriptors
-                0x22,                   // bDescriptorType
-                descr_len as u8,        // wDescriptorLength
-                (descr_len >> 8) as u8, // wDescriptorLength
-            ],
-        )?;
+        writer.write(HID_DESCRIPTOR, &self.hid_descriptor())?;
 
         writer.endpoint(&self.report_ep)?;
 
@@ -102,69 +399,588 @@ impl<B: UsbBus> UsbClass<B> for HIDClass<'_, B> {
             return;
         }
 
+        // the idle rate is per report, there is only one input report
+        let [duration, report_id] = req.value.to_be_bytes();
+        if req.request == SET_IDLE && (report_id == 0 || report_id == INPUT_REPORT_ID) {
+            self.idle = duration;
+            xfer.accept().ok();
+            return;
+        }
+        // only the report protocol is supported, as a joystick is not a boot device
+        if req.request == SET_PROTOCOL && req.value == REPORT_PROTOCOL as u16 {
+            xfer.accept().ok();
+            return;
+        }
+
+        // REQ_SET_REPORT of the model output report
+        let [report_type, report_id] = req.value.to_be_bytes();
+        if req.request == SET_REPORT && report_type == OUTPUT_REPORT && report_id == MODEL_REPORT_ID
+        {
+            let request = match *xfer.data() {
+                [_, 0, index, ..] => Some(Request::Select(index)),
+                [_, 1, from, to, ..] => Some(Request::Copy { from, to }),
+                [_, 2, index, ..] => Some(Request::Reset(index)),
+                [_, 3, index, template, ..] => Template::ALL
+                    .get(template as usize)
+                    .map(|&template| Request::Create { index, template }),
+                _ => None,
+            };
+            if request.is_some() {
+                self.model_request = request;
+                xfer.accept().ok();
+                return;
+            }
+        }
+
+        // REQ_SET_REPORT of the global variable output report
+        if req.request == SET_REPORT
+            && report_type == OUTPUT_REPORT
+            && report_id == GLOBAL_REPORT_ID
+        {
+            if let [_, mode, index, lo, hi, ..] = *xfer.data() {
+                self.global_request = Some((mode, index, i16::from_le_bytes([lo, hi])));
+                xfer.accept().ok();
+                return;
+            }
+        }
+
+        // REQ_SET_REPORT of the configuration output report
+        if req.request == SET_REPORT
+            && report_type == OUTPUT_REPORT
+            && report_id == CONFIG_REPORT_ID
+            && self.write_config(xfer.data())
+        {
+            xfer.accept().ok();
+            return;
+        }
+
+        // REQ_SET_REPORT of the model data feature report
+        if req.request == SET_REPORT
+            && report_type == FEATURE_REPORT
+            && report_id == MODEL_DATA_REPORT_ID
+            && self.write_model(xfer.data())
+        {
+            xfer.accept().ok();
+            return;
+        }
+
         //Pass the request on
         xfer.reject().ok();
     }
 
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
+        if req.index != u8::from(self.report_if) as u16
+            || req.recipient != control::Recipient::Interface
+        {
+            return;
+        }
 
         if req.request_type == control::RequestType::Standard {
-            match (req.recipient, req.request) {
-                (control::Recipient::Interface, control::Request::GET_DESCRIPTOR) => {
-                    let (dtype, _index) = req.descriptor_type_index();
-                    if dtype == 0x21 {
-                        // HID descriptor
-                        cortex_m::asm::bkpt();
-                        let descr_len: u16 = REPORT_DESCR.len() as u16;
-
-                        // HID descriptor
-                        let descr = &[
-                            0x09,                   // length
-                            0x21,                   // descriptor type
-                            0x01,                   // bcdHID
-                            0x01,                   // bcdHID
-                            0x00,                   // bCountryCode
-                            0x01,                   // bNumDescriptors
-                            0x22,                   // bDescriptorType
-                            descr_len as u8,        // wDescriptorLength
-                            (descr_len >> 8) as u8, // wDescriptorLength
-                        ];
-
-                        xfer.accept_with(descr).ok();
-                        return;
-                    } else if dtype == 0x22 {
-                        // Report descriptor
-                        xfer.accept_with(REPORT_DESCR).ok();
-                        return;
+            if req.request == control::Request::GET_DESCRIPTOR {
+                match req.descriptor_type_index() {
+                    (HID_DESCRIPTOR, _) => {
+                        let mut descr = [0; 9];
+                        descr[0] = descr.len() as u8;
+                        descr[1] = HID_DESCRIPTOR;
+                        descr[2..].copy_from_slice(&self.hid_descriptor());
+                        xfer.accept_with(&descr).ok();
                     }
+                    (REPORT_DESCRIPTOR, _) => {
+                        xfer.accept_with(self.descriptor.as_bytes()).ok();
+                    }
+                    _ => {}
                 }
-                _ => {
-                    return;
-                }
-            };
+            }
+            return;
         }
-
-        // If request is meant for the usb class
-        if !(req.request_type == control::RequestType::Class
-            && req.recipient == control::Recipient::Interface
-            && req.index == u8::from(self.report_if) as u16)
-        {
-            //Ignore it because we dont take any requests
+        if req.request_type != control::RequestType::Class {
             return;
         }
 
         match req.request {
-            0x01 => {
-                // REQ_GET_REPORT
-                // USB host requests for report
-                // Just send an empty report
-                xfer.accept_with(&[0, 0, 0, 0]).ok();
+            GET_REPORT => {
+                match req.value.to_be_bytes() {
+                    [INPUT_REPORT, INPUT_REPORT_ID] => {
+                        xfer.accept_with(&self.report[..self.report_len])
+                    }
+                    [FEATURE_REPORT, TIMER_REPORT_ID] => xfer.accept_with(&self.timer_report),
+                    [FEATURE_REPORT, ANALOG_REPORT_ID] => xfer.accept_with(&self.analog_report),
+                    [FEATURE_REPORT, CALIBRATION_REPORT_ID] => {
+                        xfer.accept_with(&self.calibration_report)
+                    }
+                    [FEATURE_REPORT, VERSION_REPORT_ID] => {
+                        let [major, minor, patch] = FIRMWARE_VERSION;
+                        xfer.accept_with(&[VERSION_REPORT_ID, major, minor, patch, config::VERSION])
+                    }
+                    [FEATURE_REPORT, STATUS_REPORT_ID] => xfer.accept_with(&self.status_report),
+                    [FEATURE_REPORT, MODEL_DATA_REPORT_ID] => {
+                        xfer.accept_with(&self.model_report())
+                    }
+                    _ => xfer.reject(),
+                }
+                .ok();
+            }
+            GET_IDLE => {
+                xfer.accept_with(&[self.idle]).ok();
+            }
+            GET_PROTOCOL => {
+                xfer.accept_with(&[REPORT_PROTOCOL]).ok();
             }
             _ => {
-                //Pass request on
                 xfer.reject().ok();
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::CONFIG_REPORT_LEN;
+    use crate::templates::Template;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use usb_device::bus::PollResult;
+    use usb_device::prelude::*;
+    use usb_device::{UsbDirection, UsbError};
+
+    const CLASS_IN: u8 = 0xA1;
+    const CLASS_OUT: u8 = 0x21;
+    const MAX_PACKET: usize = 64;
+
+    /// What the host sent and received
+    #[derive(Default)]
+    struct Host {
+        setup: Option<[u8; 8]>,
+        out: VecDeque<std::vec::Vec<u8>>,
+        /// Data of the control transfer sent to the host
+        control_in: std::vec::Vec<u8>,
+        in_pending: bool,
+        stalled: bool,
+        /// Reports of the interrupt endpoint
+        reports: std::vec::Vec<std::vec::Vec<u8>>,
+    }
+
+    /// Bus of a host doing one control transfer at a time
+    struct MockBus {
+        host: Arc<Mutex<Host>>,
+        endpoints: usize,
+    }
+
+    impl UsbBus for MockBus {
+        fn alloc_ep(
+            &mut self,
+            ep_dir: UsbDirection,
+            ep_addr: Option<EndpointAddress>,
+            _: EndpointType,
+            _: u16,
+            _: u8,
+        ) -> Result<EndpointAddress> {
+            Ok(ep_addr.unwrap_or_else(|| {
+                self.endpoints += 1;
+                EndpointAddress::from_parts(self.endpoints, ep_dir)
+            }))
+        }
+
+        fn enable(&mut self) {}
+
+        fn reset(&self) {}
+
+        fn set_device_address(&self, _: u8) {}
+
+        fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+            let mut host = self.host.lock().unwrap();
+            if ep_addr.index() == 0 {
+                host.control_in.extend_from_slice(buf);
+                host.in_pending = true;
+            } else {
+                host.reports.push(buf.to_vec());
+            }
+            Ok(buf.len())
+        }
+
+        fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+            let mut host = self.host.lock().unwrap();
+            let data = match host.setup.take() {
+                Some(setup) if ep_addr.index() == 0 => setup.to_vec(),
+                _ => host.out.pop_front().ok_or(UsbError::WouldBlock)?,
+            };
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        }
+
+        fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+            if ep_addr.direction() == UsbDirection::In {
+                self.host.lock().unwrap().stalled = stalled;
+            }
+        }
+
+        fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+            ep_addr.direction() == UsbDirection::In && self.host.lock().unwrap().stalled
+        }
+
+        fn suspend(&self) {}
+
+        fn resume(&self) {}
+
+        fn poll(&self) -> PollResult {
+            let mut host = self.host.lock().unwrap();
+            let (ep_out, ep_in_complete, ep_setup) = if host.in_pending {
+                host.in_pending = false;
+                (0, 1, 0)
+            } else if host.setup.is_some() {
+                (0, 0, 1)
+            } else if !host.out.is_empty() {
+                (1, 0, 0)
+            } else {
+                return PollResult::None;
+            };
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+
+    /// Device with the class, as the host sees it
+    struct Usb {
+        host: Arc<Mutex<Host>>,
+        device: UsbDevice<'static, MockBus>,
+        class: HIDClass<'static, MockBus>,
+    }
+
+    impl Usb {
+        fn new(layout: Layout) -> Self {
+            let host = Arc::new(Mutex::new(Host::default()));
+            let bus = MockBus {
+                host: host.clone(),
+                endpoints: 0,
+            };
+            let alloc = Box::leak(Box::new(UsbBusAllocator::new(bus)));
+            let class = HIDClass::new(alloc, layout);
+            let device = UsbDeviceBuilder::new(alloc, UsbVidPid(0x16c0, 0x27dc))
+                .max_packet_size_0(MAX_PACKET as u8)
+                .build();
+            Self {
+                host,
+                device,
+                class,
+            }
+        }
+
+        fn run(&mut self) {
+            for _ in 0..16 {
+                self.device.poll(&mut [&mut self.class]);
+            }
+        }
+
+        /// Control transfer with the class, `None` if it stalled
+        fn transfer(&mut self, setup: [u8; 8], data: &[u8]) -> Option<std::vec::Vec<u8>> {
+            {
+                let mut host = self.host.lock().unwrap();
+                host.setup = Some(setup);
+                host.out = data.chunks(MAX_PACKET).map(|c| c.to_vec()).collect();
+                host.control_in.clear();
+                host.stalled = false;
+            }
+            self.run();
+            let received = {
+                let mut host = self.host.lock().unwrap();
+                if host.stalled {
+                    return None;
+                }
+                if setup[0] & 0x80 != 0 {
+                    // status stage of a read
+                    host.out.push_back(std::vec::Vec::new());
+                }
+                std::mem::take(&mut host.control_in)
+            };
+            self.run();
+            Some(received)
+        }
+
+        fn get_report(&mut self, report_type: u8, id: u8, len: u16) -> Option<std::vec::Vec<u8>> {
+            let [lo, hi] = len.to_le_bytes();
+            self.transfer([CLASS_IN, GET_REPORT, id, report_type, 0, 0, lo, hi], &[])
+        }
+
+        /// Write a report of `report_type`
+        fn write_report(&mut self, report_type: u8, data: &[u8]) -> Option<std::vec::Vec<u8>> {
+            let [lo, hi] = (data.len() as u16).to_le_bytes();
+            let setup = [CLASS_OUT, SET_REPORT, data[0], report_type, 0, 0, lo, hi];
+            self.transfer(setup, data)
+        }
+
+        /// Write a feature report
+        fn set_report(&mut self, data: &[u8]) -> Option<std::vec::Vec<u8>> {
+            self.write_report(FEATURE_REPORT, data)
+        }
+
+        /// Send an output report
+        fn output(&mut self, data: &[u8]) -> Option<std::vec::Vec<u8>> {
+            self.write_report(OUTPUT_REPORT, data)
+        }
+
+        fn reports(&self) -> std::vec::Vec<std::vec::Vec<u8>> {
+            self.host.lock().unwrap().reports.clone()
+        }
+    }
+
+    #[test]
+    fn get_report() {
+        let mut usb = Usb::new(Layout::JOYSTICK);
+        let state = JoystickState {
+            buttons: 0b101,
+            ..Default::default()
+        };
+        usb.class.write(&state, 0);
+        let mut expected = [0; MAX_REPORT];
+        let len = Layout::JOYSTICK.write(&state, &mut expected);
+        assert_eq!(usb.reports(), [expected[..len].to_vec()]);
+
+        let report = usb.get_report(INPUT_REPORT, INPUT_REPORT_ID, 64);
+        assert_eq!(report.unwrap(), expected[..len]);
+        // shorter requests get the start of the report
+        let report = usb.get_report(INPUT_REPORT, INPUT_REPORT_ID, 3);
+        assert_eq!(report.unwrap(), expected[..3]);
+
+        usb.class.set_status(0x01, 2, 3, 4, 0b10);
+        let status = usb.get_report(FEATURE_REPORT, STATUS_REPORT_ID, 64);
+        assert_eq!(status.unwrap(), [STATUS_REPORT_ID, 0x01, 2, 3, 4, 0, 0b10]);
+        let version = usb.get_report(FEATURE_REPORT, VERSION_REPORT_ID, 64);
+        assert_eq!(version.unwrap()[4], config::VERSION);
+
+        // unknown reports stall
+        assert_eq!(usb.get_report(INPUT_REPORT, 0x02, 64), None);
+        assert_eq!(usb.get_report(FEATURE_REPORT, 0x7F, 64), None);
+    }
+
+    #[test]
+    fn idle_rate() {
+        let mut usb = Usb::new(Layout::JOYSTICK);
+        let state = JoystickState::default();
+
+        // without an idle rate only changes are sent
+        usb.class.write(&state, 0);
+        usb.class.write(&state, 1000);
+        assert_eq!(usb.reports().len(), 1);
+
+        // 20 ms for the input report
+        let set_idle = |duration, id| [CLASS_OUT, SET_IDLE, id, duration, 0, 0, 0, 0];
+        let get_idle = [CLASS_IN, GET_IDLE, INPUT_REPORT_ID, 0, 0, 0, 1, 0];
+        assert!(usb.transfer(set_idle(5, INPUT_REPORT_ID), &[]).is_some());
+        assert_eq!(usb.transfer(get_idle, &[]), Some(vec![5]));
+        // the last report is older than the new rate already
+        usb.class.write(&state, 10);
+        assert_eq!(usb.reports().len(), 2);
+        usb.class.write(&state, 10);
+        assert_eq!(usb.reports().len(), 2);
+        usb.class.write(&state, 10);
+        assert_eq!(usb.reports().len(), 3);
+
+        // there is no other input report to set it for
+        assert_eq!(usb.transfer(set_idle(5, 0x02), &[]), None);
+        // only changes again, for all reports
+        assert!(usb.transfer(set_idle(0, 0), &[]).is_some());
+        assert_eq!(usb.transfer(get_idle, &[]), Some(vec![0]));
+        usb.class.write(&state, 1000);
+        assert_eq!(usb.reports().len(), 3);
+    }
+
+    #[test]
+    fn protocol() {
+        let mut usb = Usb::new(Layout::JOYSTICK);
+        let get = [CLASS_IN, GET_PROTOCOL, 0, 0, 0, 0, 1, 0];
+        let set = |protocol| [CLASS_OUT, SET_PROTOCOL, protocol, 0, 0, 0, 0, 0];
+        assert_eq!(usb.transfer(get, &[]), Some(vec![REPORT_PROTOCOL]));
+        assert!(usb.transfer(set(REPORT_PROTOCOL), &[]).is_some());
+        // a joystick has no boot protocol
+        assert_eq!(usb.transfer(set(0), &[]), None);
+    }
+
+    /// The output reports are commands, they can neither be read nor written as features
+    fn write_only(usb: &mut Usb, report: &[u8]) {
+        for report_type in [OUTPUT_REPORT, FEATURE_REPORT].iter() {
+            assert_eq!(usb.get_report(*report_type, report[0], 64), None);
+        }
+        assert_eq!(usb.set_report(report), None);
+    }
+
+    #[test]
+    fn model_output_report() {
+        let mut usb = Usb::new(Layout::JOYSTICK);
+        assert!(usb.output(&[MODEL_REPORT_ID, 0, 3, 0]).is_some());
+        assert_eq!(usb.class.take_model_request(), Some(Request::Select(3)));
+        assert_eq!(usb.class.take_model_request(), None);
+        assert_eq!(usb.output(&[MODEL_REPORT_ID, 3, 1, 99]), None);
+        write_only(&mut usb, &[MODEL_REPORT_ID, 0, 3, 0]);
+        assert_eq!(usb.class.take_model_request(), None);
+    }
+
+    #[test]
+    fn global_output_report() {
+        let mut usb = Usb::new(Layout::JOYSTICK);
+        assert!(usb.output(&[GLOBAL_REPORT_ID, 1, 2, 0xFF, 0xFF]).is_some());
+        assert_eq!(usb.class.take_global_request(), Some((1, 2, -1)));
+        write_only(&mut usb, &[GLOBAL_REPORT_ID, 1, 2, 0xFF, 0xFF]);
+        assert_eq!(usb.class.take_global_request(), None);
+    }
+
+    #[test]
+    fn config_output_report() {
+        let mut usb = Usb::new(Layout::JOYSTICK);
+        let mut config = Config::default();
+        config.usb.product.clear();
+        config.usb.product.push_str("Glider Trainer").unwrap();
+        let mut buf = [0; CONFIG_SIZE];
+        let mut w = Writer::new(&mut buf);
+        config.encode(&mut w);
+        let len = w.finish().unwrap();
+        let data = &buf[..len];
+        assert!(data.len() > CONFIG_CHUNK);
+
+        for (i, chunk) in data.chunks(CONFIG_CHUNK).enumerate() {
+            let mut report = [0; CONFIG_REPORT_LEN];
+            report[0] = CONFIG_REPORT_ID;
+            report[2..4].copy_from_slice(&((i * CONFIG_CHUNK) as u16).to_le_bytes());
+            report[4..4 + chunk.len()].copy_from_slice(chunk);
+            assert!(usb.output(&report).is_some());
+        }
+        // a length cutting the configuration short does not decode
+        let [lo, hi] = (data.len() as u16 - 1).to_le_bytes();
+        assert_eq!(usb.output(&[CONFIG_REPORT_ID, 1, lo, hi]), None);
+        let [lo, hi] = (data.len() as u16).to_le_bytes();
+        assert_eq!(usb.class.take_config_request(), None);
+        assert!(usb.output(&[CONFIG_REPORT_ID, 1, lo, hi]).is_some());
+        assert_eq!(usb.class.take_config_request(), Some(config));
+
+        write_only(&mut usb, &[CONFIG_REPORT_ID, 1, lo, hi]);
+        assert_eq!(usb.class.take_config_request(), None);
+    }
+
+    fn encoding(model: &Model) -> std::vec::Vec<u8> {
+        let mut buf = [0; MODEL_SIZE];
+        let mut w = Writer::new(&mut buf);
+        model.encode(&mut w);
+        let len = w.finish().unwrap();
+        buf[..len].to_vec()
+    }
+
+    /// Write a model through the model data report, `None` if a report stalled
+    fn write_model(usb: &mut Usb, data: &[u8], version: u8) -> Option<()> {
+        for (i, chunk) in data.chunks(MODEL_CHUNK).enumerate() {
+            let mut report = [0; MODEL_DATA_REPORT_LEN];
+            report[0] = MODEL_DATA_REPORT_ID;
+            report[2..4].copy_from_slice(&((i * MODEL_CHUNK) as u16).to_le_bytes());
+            report[4..4 + chunk.len()].copy_from_slice(chunk);
+            usb.set_report(&report)?;
+        }
+        let [lo, hi] = (data.len() as u16).to_le_bytes();
+        usb.set_report(&[MODEL_DATA_REPORT_ID, 1, lo, hi, version])?;
+        Some(())
+    }
+
+    /// Read the active model through the model data report, `model` is what the firmware passes
+    /// once asked for it
+    fn read_model(usb: &mut Usb, model: &Model) -> std::vec::Vec<u8> {
+        usb.set_report(&[MODEL_DATA_REPORT_ID, 2]).unwrap();
+        let report = usb.get_report(FEATURE_REPORT, MODEL_DATA_REPORT_ID, 64);
+        assert_eq!(report.unwrap()[1], 0, "not prepared yet");
+        assert!(usb.class.take_model_read_request());
+        usb.class.set_model(model);
+
+        let mut data = std::vec::Vec::new();
+        loop {
+            let [lo, hi] = (data.len() as u16).to_le_bytes();
+            usb.set_report(&[MODEL_DATA_REPORT_ID, 3, lo, hi]).unwrap();
+            let report = usb.get_report(FEATURE_REPORT, MODEL_DATA_REPORT_ID, 64);
+            let report = report.unwrap();
+            assert_eq!(report[..2], [MODEL_DATA_REPORT_ID, config::VERSION]);
+            let len = u16::from_le_bytes([report[2], report[3]]) as usize;
+            let chunk = (len - data.len()).min(MODEL_CHUNK);
+            data.extend_from_slice(&report[4..4 + chunk]);
+            if data.len() == len {
+                return data;
+            }
+        }
+    }
+
+    #[test]
+    fn model_is_read() {
+        let mut usb = Usb::new(Layout::JOYSTICK);
+        let model = Template::Flaperons.model();
+        assert_eq!(read_model(&mut usb, &model), encoding(&model));
+        assert!(!usb.class.take_model_read_request());
+    }
+
+    #[test]
+    fn model_is_written() {
+        let mut usb = Usb::new(Layout::JOYSTICK);
+        let mut model = Template::Multirotor.model();
+        model.name.push_str("Quad").unwrap();
+        let data = encoding(&model);
+        assert!(data.len() > MODEL_CHUNK);
+
+        // models of other settings versions are not taken
+        assert_eq!(write_model(&mut usb, &data, config::VERSION - 1), None);
+        assert_eq!(usb.class.take_model_data(), None);
+        write_model(&mut usb, &data, config::VERSION).unwrap();
+        assert_eq!(usb.class.take_model_data(), Some(&data[..]));
+        assert_eq!(usb.class.take_model_data(), None);
+
+        // the firmware passes the applied model, which is read back
+        assert!(usb.class.take_model_read_request());
+        usb.class.set_model(&model);
+        assert_eq!(read_model(&mut usb, &model), data);
+    }
+
+    #[test]
+    fn model_writes_are_not_read() {
+        let mut usb = Usb::new(Layout::JOYSTICK);
+        let model = Model::default();
+        read_model(&mut usb, &model);
+        // a started write hides the model until it is asked for again
+        let mut chunk = [0; MODEL_DATA_REPORT_LEN];
+        chunk[0] = MODEL_DATA_REPORT_ID;
+        usb.set_report(&chunk).unwrap();
+        usb.class.set_model(&model);
+        let report = usb.get_report(FEATURE_REPORT, MODEL_DATA_REPORT_ID, 64);
+        assert_eq!(report.unwrap()[1], 0);
+        assert_eq!(read_model(&mut usb, &model), encoding(&model));
+        // committing needs chunks
+        assert_eq!(
+            usb.set_report(&[MODEL_DATA_REPORT_ID, 1, 1, 0, config::VERSION]),
+            None
+        );
+    }
+
+    #[test]
+    fn written_layout_needs_enumeration() {
+        let mut usb = Usb::new(Layout::JOYSTICK);
+        let mut model = Template::Plane.model();
+        model.hid_layout.axis_bits = 12;
+        model.hid_layout.axis_max = 2047;
+        model.hid_layout.buttons = 16;
+        write_model(&mut usb, &encoding(&model), config::VERSION).unwrap();
+
+        // the firmware applies the written model and its layout
+        let mut r = Reader::new(usb.class.take_model_data().unwrap());
+        let written = Model::decode(&mut r).unwrap();
+        assert!(r.is_done());
+        assert!(usb.class.set_layout(written.hid_layout));
+        assert!(usb.class.layout_changed());
+        assert!(!usb.class.set_layout(written.hid_layout));
+        usb.class.set_status(0, 0, 0, 0, 0);
+        let status = usb.get_report(FEATURE_REPORT, STATUS_REPORT_ID, 64);
+        assert_eq!(status.unwrap()[5], 1);
+
+        // back to the enumerated layout
+        assert!(!usb.class.set_layout(Layout::JOYSTICK));
+        assert!(!usb.class.layout_changed());
+        usb.class.set_status(0, 0, 0, 0, 0);
+        let status = usb.get_report(FEATURE_REPORT, STATUS_REPORT_ID, 64);
+        assert_eq!(status.unwrap()[5], 0);
+    }
+}
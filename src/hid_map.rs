@@ -0,0 +1,200 @@
+//! Mapping of switches to the HID report
+//!
+//! Simulators differ in what they make of a switch, so each mapping passes a switch to the host
+//! as a button, a set of buttons, an axis, a hat direction or a short pulse on every change. A
+//! switch has two positions, or three if it has a second input for its low position.
+use crate::config::HID_BUTTONS;
+use crate::mixer::{Inputs, Switch};
+use crate::types::{Value, REPORT_AXES, REPORT_HATS, VALUE_MAX};
+
+/// Number of mappings of a model
+pub const HID_MAPPINGS: usize = 16;
+/// How long a pulse presses its button, in milliseconds
+const PULSE_TIME: u32 = 100;
+/// Hat switch value without a direction
+pub const HAT_CENTER: u8 = 8;
+
+/// Direction of the hat switch
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    Up,
+    Right,
+    Down,
+    Left,
+}
+
+/// Where a switch ends up in the report
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+    /// Button pressed in the high position
+    Button(u8),
+    /// One button per position, starting with the given one for the low position
+    Buttons(u8),
+    /// Report axis, at its minimum in the low and its maximum in the high position
+    Axis(u8),
+    /// Direction of a hat switch pressed in the high position
+    Hat { hat: u8, direction: Direction },
+    /// Button pressed for a moment on every change of the position
+    Pulse(u8),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HidMapping {
+    pub high: Switch,
+    /// Low position of a three position switch, the middle one is neither
+    pub low: Option<Switch>,
+    pub target: Target,
+}
+
+impl HidMapping {
+    /// Button pressed while `switch` is on
+    pub const fn button(switch: Switch, button: u8) -> Self {
+        Self {
+            high: switch,
+            low: None,
+            target: Target::Button(button),
+        }
+    }
+
+    pub fn positions(&self) -> u8 {
+        2 + self.low.is_some() as u8
+    }
+
+    /// Whether the buttons and axes of the target exist
+    pub fn is_valid(&self) -> bool {
+        let button = |b: u8| (b as usize) < HID_BUTTONS;
+        match self.target {
+            Target::Button(b) | Target::Pulse(b) => button(b),
+            Target::Buttons(b) => button(b.saturating_add(self.positions() - 1)),
+            Target::Axis(axis) => (axis as usize) < REPORT_AXES,
+            Target::Hat { hat, .. } => (hat as usize) < REPORT_HATS,
+        }
+    }
+
+    /// Position from low `0` to high
+    fn position(&self, inputs: &Inputs) -> u8 {
+        match self.low {
+            _ if inputs.is_on(self.high) => self.positions() - 1,
+            Some(low) if !inputs.is_on(low) => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Part of the report driven by the mappings
+#[derive(Copy, Clone, Debug)]
+pub struct Mapped {
+    /// Pressed buttons, one bit per button
+    pub buttons: u128,
+    pub hats: [u8; REPORT_HATS],
+    /// Axes taking the place of the channels
+    pub axes: [Option<Value>; REPORT_AXES],
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct State {
+    /// Position of the previous frame, `None` before the first one
+    position: Option<u8>,
+    /// Start of the running pulse
+    pulse_at: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HidMap {
+    pub config: [Option<HidMapping>; HID_MAPPINGS],
+    state: [State; HID_MAPPINGS],
+}
+
+impl Default for HidMap {
+    /// The first digital inputs and logical switches as buttons
+    fn default() -> Self {
+        let mut config = [None; HID_MAPPINGS];
+        let switches = [
+            Switch::Input(0),
+            Switch::Input(1),
+            Switch::Logical(0),
+            Switch::Logical(1),
+            Switch::Logical(2),
+            Switch::Logical(3),
+        ];
+        for (i, (mapping, switch)) in config.iter_mut().zip(switches.iter()).enumerate() {
+            *mapping = Some(HidMapping::button(*switch, i as u8));
+        }
+        Self {
+            config,
+            state: Default::default(),
+        }
+    }
+}
+
+impl HidMap {
+    /// Evaluate all mappings, `now` is in milliseconds
+    pub fn evaluate(&mut self, inputs: &Inputs, now: u32) -> Mapped {
+        let mut mapped = Mapped {
+            buttons: 0,
+            hats: [HAT_CENTER; REPORT_HATS],
+            axes: [None; REPORT_AXES],
+        };
+        let mut directions = [[false; 4]; REPORT_HATS];
+        for (mapping, state) in self.config.iter().zip(self.state.iter_mut()) {
+            let mapping = match mapping {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+            let position = mapping.position(inputs);
+            let changed = state.position.is_some_and(|p| p != position);
+            state.position = Some(position);
+            let high = position == mapping.positions() - 1;
+            let press = |button: u8| 1u128.checked_shl(button as u32).unwrap_or(0);
+
+            match mapping.target {
+                Target::Button(button) if high => mapped.buttons |= press(button),
+                Target::Button(_) => {}
+                Target::Buttons(first) => mapped.buttons |= press(first + position),
+                Target::Axis(axis) => {
+                    let range = 2 * VALUE_MAX / (mapping.positions() as i32 - 1);
+                    if let Some(v) = mapped.axes.get_mut(axis as usize) {
+                        *v = Some(position as Value * range - VALUE_MAX);
+                    }
+                }
+                Target::Hat { hat, direction } => {
+                    if let Some(directions) = directions.get_mut(hat as usize) {
+                        directions[direction as usize] |= high;
+                    }
+                }
+                Target::Pulse(button) => {
+                    if changed {
+                        state.pulse_at = Some(now);
+                    }
+                    if let Some(at) = state.pulse_at {
+                        if now.wrapping_sub(at) < PULSE_TIME {
+                            mapped.buttons |= press(button);
+                        } else {
+                            state.pulse_at = None;
+                        }
+                    }
+                }
+            }
+        }
+        mapped.hats = directions.map(hat);
+        mapped
+    }
+}
+
+/// Hat switch value of the pressed directions, clockwise from up in steps of 45°, opposite
+/// directions cancel each other
+fn hat([up, right, down, left]: [bool; 4]) -> u8 {
+    let vertical = up as i8 - down as i8;
+    let horizontal = right as i8 - left as i8;
+    match (vertical, horizontal) {
+        (1, 0) => 0,
+        (1, 1) => 1,
+        (0, 1) => 2,
+        (-1, 1) => 3,
+        (-1, 0) => 4,
+        (-1, -1) => 5,
+        (0, -1) => 6,
+        (1, -1) => 7,
+        _ => HAT_CENTER,
+    }
+}
@@ -1,5 +1,7 @@
 use core::convert::TryFrom;
 
+use crate::types::VALUE_MAX;
+
 pub struct FlySkyFsi6 {
     analog_channels: [u16; 6],
     sa: TwoWay,
@@ -32,21 +34,19 @@ impl TryFrom<&[bool; 2]> for ThreeWay {
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub enum LinearInput {
+    #[default]
     NoCalibration,
     OngoingCalibration { start: u16, end: u16 },
     Calibrated { start: u16, mid: u16, end: u16 },
 }
 
-impl Default for LinearInput {
-    fn default() -> Self {
-        Self::NoCalibration
-    }
-}
-
 impl LinearInput {
-    const RESOLUTION: u16 = 1000;
+    const RESOLUTION: u16 = 2 * VALUE_MAX as u16;
+    /// Value of a centered input
+    pub const CENTER: u16 = Self::RESOLUTION / 2;
+
     pub fn reset_calibration(&mut self) {
         *self = Self::NoCalibration;
     }
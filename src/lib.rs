@@ -0,0 +1,36 @@
+//! Everything of the radio which does not touch the hardware
+//!
+//! The firmware in `main.rs` wires these modules to the peripherals. They build for the host as
+//! well, run their tests with `cargo test-host`.
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(feature = "angle-sensors")]
+pub mod angle_sensor;
+pub mod buzzer;
+pub mod codec;
+pub mod config;
+pub mod crsf;
+pub mod curves;
+pub mod encoder;
+pub mod failsafe;
+pub mod flight_modes;
+pub mod gestures;
+pub mod globals;
+pub mod heli;
+pub mod hid;
+pub mod hid_map;
+pub mod inputs;
+pub mod limits;
+pub mod logical_switches;
+pub mod mixer;
+pub mod models;
+pub mod report;
+pub mod slow;
+pub mod special_functions;
+pub mod sticks;
+pub mod storage;
+pub mod templates;
+pub mod throttle;
+pub mod timers;
+pub mod trims;
+pub mod types;
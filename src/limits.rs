@@ -0,0 +1,145 @@
+//! Output limits of the channels
+//!
+//! Applied to the mixed channels before they are handed to any output, e.g. the USB HID report.
+use crate::types::{Value, VALUE_MAX};
+
+/// Limits of one channel, all values in tenths of a percent
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Limit {
+    /// Offset of the center
+    pub subtrim: i16,
+    /// Lower endpoint
+    pub min: i16,
+    /// Upper endpoint
+    pub max: i16,
+    pub invert: bool,
+    /// Scale both sides by the distance from the subtrim to their endpoint, so equal stick
+    /// deflections give equal travel in both directions
+    pub symmetrical: bool,
+    /// Pulse width of the center in microseconds, for outputs using pulse widths
+    pub ppm_center: u16,
+}
+
+impl Default for Limit {
+    fn default() -> Self {
+        Self {
+            subtrim: 0,
+            min: -1000,
+            max: 1000,
+            invert: false,
+            symmetrical: false,
+            ppm_center: 1500,
+        }
+    }
+}
+
+impl Limit {
+    /// Largest endpoint in either direction
+    pub const MAX_ENDPOINT: i16 = 1500;
+    /// Pulse width from the center to full deflection in microseconds
+    const PPM_RANGE: i32 = 500;
+
+    fn to_value(permille: i16) -> Value {
+        permille as Value * VALUE_MAX / 1000
+    }
+
+    /// Apply the limits to a channel
+    pub fn apply(&self, v: Value) -> Value {
+        let subtrim = Self::to_value(self.subtrim) as i64;
+        let min = Self::to_value(self.min.max(-Self::MAX_ENDPOINT)) as i64;
+        let max = Self::to_value(self.max.min(Self::MAX_ENDPOINT)) as i64;
+
+        let scale = match (v > 0, self.symmetrical) {
+            (true, false) => max,
+            (false, false) => -min,
+            (true, true) => max - subtrim,
+            (false, true) => subtrim - min,
+        };
+        // never panics, even for limits which were not decoded
+        let v = (v as i64 * scale / VALUE_MAX as i64 + subtrim)
+            .min(max)
+            .max(min) as Value;
+
+        if self.invert {
+            -v
+        } else {
+            v
+        }
+    }
+
+    /// Pulse width in microseconds of a limited channel
+    pub fn pulse_width(&self, v: Value) -> u16 {
+        (self.ppm_center as i32 + v * Self::PPM_RANGE / VALUE_MAX) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Decode, Encode, Error, Reader, Writer};
+
+    fn decode(limit: &Limit) -> Result<Limit, Error> {
+        let mut buf = [0; 16];
+        let mut w = Writer::new(&mut buf);
+        limit.encode(&mut w);
+        let len = w.finish().unwrap();
+        Limit::decode(&mut Reader::new(&buf[..len]))
+    }
+
+    #[test]
+    fn decoded_limits_are_bounded() {
+        let limit = Limit {
+            subtrim: 100,
+            min: -Limit::MAX_ENDPOINT,
+            max: Limit::MAX_ENDPOINT,
+            invert: true,
+            symmetrical: true,
+            ppm_center: 1520,
+        };
+        assert_eq!(decode(&limit), Ok(limit));
+        let invalid = [
+            Limit {
+                min: 500,
+                max: 400,
+                ..limit
+            },
+            Limit {
+                subtrim: Limit::MAX_ENDPOINT + 1,
+                ..limit
+            },
+            Limit {
+                subtrim: i16::MIN,
+                ..limit
+            },
+            Limit {
+                min: i16::MIN,
+                ..limit
+            },
+            Limit {
+                max: i16::MAX,
+                ..limit
+            },
+        ];
+        for limit in invalid.iter() {
+            assert_eq!(decode(limit), Err(Error::Invalid), "{:?}", limit);
+        }
+    }
+
+    #[test]
+    fn extreme_limits() {
+        let limit = Limit {
+            subtrim: i16::MIN,
+            min: i16::MAX,
+            max: i16::MIN,
+            symmetrical: true,
+            ..Default::default()
+        };
+        for v in [i32::MIN, -VALUE_MAX, 0, VALUE_MAX, i32::MAX].iter() {
+            limit.apply(*v);
+        }
+        let limit = Limit::default();
+        assert_eq!(limit.apply(VALUE_MAX / 2), VALUE_MAX / 2);
+        assert_eq!(limit.apply(2 * VALUE_MAX), VALUE_MAX);
+        assert_eq!(limit.apply(-2 * VALUE_MAX), -VALUE_MAX);
+    }
+}
@@ -0,0 +1,183 @@
+//! Logical switches
+//!
+//! Virtual switches computed from conditions on sources and other switches. They are evaluated
+//! in order once per frame, a logical switch referring to a later one sees its state of the
+//! previous frame. All times are in tenths of a second and measured with the timestamps passed
+//! to [`LogicalSwitches::evaluate`].
+use crate::mixer::{Condition, Inputs, Source, Switch};
+use crate::types::{Value, VALUE_MAX};
+
+/// Number of logical switches
+pub const LOGICAL_SWITCHES: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Function {
+    Off,
+    /// `a > x`, `x` in percent
+    Greater(Source, i16),
+    /// `a < x`, `x` in percent
+    Less(Source, i16),
+    /// `|a| > x`, `x` in percent
+    AbsGreater(Source, i16),
+    /// `|a| < x`, `x` in percent
+    AbsLess(Source, i16),
+    /// `a > b`
+    SourceGreater(Source, Source),
+    And(Switch, Switch),
+    Or(Switch, Switch),
+    Xor(Switch, Switch),
+    /// On for one frame when `switch` is released after being on for `min..=max`, no upper bound
+    /// if `max` is `0`
+    Edge {
+        switch: Switch,
+        min: u16,
+        max: u16,
+    },
+    /// Turned on by `set` and off by `reset`
+    Sticky {
+        set: Switch,
+        reset: Switch,
+    },
+    /// Pulses, on for `on` and then off for `off`
+    Timer {
+        on: u16,
+        off: u16,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LogicalSwitch {
+    pub function: Function,
+    /// Additional condition, the switch is only on if it holds
+    pub and: Condition,
+    /// Time the function has to be true before the switch turns on
+    pub delay: u16,
+    /// Minimum time the switch stays on, once turned on
+    pub duration: u16,
+}
+
+impl Default for LogicalSwitch {
+    fn default() -> Self {
+        Self {
+            function: Function::Off,
+            and: Condition::Always,
+            delay: 0,
+            duration: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct State {
+    on: bool,
+    /// Since when the function is true
+    true_since: Option<u32>,
+    /// End of the minimum duration
+    on_until: Option<u32>,
+    /// Memory of sticky switches, start of edges and phase of timers
+    latch: bool,
+    since: u32,
+}
+
+/// Whether `now` is at or after `t`, robust against wrapping timestamps
+fn reached(now: u32, t: u32) -> bool {
+    (now.wrapping_sub(t) as i32) >= 0
+}
+
+fn millis(tenths: u16) -> u32 {
+    tenths as u32 * 100
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LogicalSwitches {
+    pub config: [LogicalSwitch; LOGICAL_SWITCHES],
+    state: [State; LOGICAL_SWITCHES],
+}
+
+impl LogicalSwitches {
+    /// States of all logical switches
+    pub fn states(&self) -> [bool; LOGICAL_SWITCHES] {
+        self.state.map(|s| s.on)
+    }
+
+    /// Evaluate all switches, `now` is in milliseconds
+    pub fn evaluate(&mut self, inputs: &Inputs, now: u32) {
+        let mut states = self.states();
+        for i in 0..LOGICAL_SWITCHES {
+            let inputs = Inputs {
+                logical: &states,
+                ..*inputs
+            };
+            let on = self.evaluate_one(i, &inputs, now);
+            self.state[i].on = on;
+            states[i] = on;
+        }
+    }
+
+    fn evaluate_one(&mut self, i: usize, inputs: &Inputs, now: u32) -> bool {
+        let config = self.config[i];
+        let state = &mut self.state[i];
+        let percent = |x: i16| x as Value * VALUE_MAX / 100;
+
+        let value = match config.function {
+            Function::Off => false,
+            Function::Greater(a, x) => inputs.value(a) > percent(x),
+            Function::Less(a, x) => inputs.value(a) < percent(x),
+            Function::AbsGreater(a, x) => inputs.value(a).abs() > percent(x),
+            Function::AbsLess(a, x) => inputs.value(a).abs() < percent(x),
+            Function::SourceGreater(a, b) => inputs.value(a) > inputs.value(b),
+            Function::And(a, b) => inputs.is_on(a) && inputs.is_on(b),
+            Function::Or(a, b) => inputs.is_on(a) || inputs.is_on(b),
+            Function::Xor(a, b) => inputs.is_on(a) != inputs.is_on(b),
+            Function::Edge { switch, min, max } => {
+                let on = inputs.is_on(switch);
+                let mut edge = false;
+                if on && !state.latch {
+                    state.since = now;
+                } else if !on && state.latch {
+                    let held = now.wrapping_sub(state.since);
+                    edge = held >= millis(min) && (max == 0 || held <= millis(max));
+                }
+                state.latch = on;
+                edge
+            }
+            Function::Sticky { set, reset } => {
+                if inputs.is_on(reset) {
+                    state.latch = false;
+                } else if inputs.is_on(set) {
+                    state.latch = true;
+                }
+                state.latch
+            }
+            Function::Timer { on, off } => {
+                let phase = if state.latch { on } else { off };
+                if reached(now, state.since.wrapping_add(millis(phase))) {
+                    state.latch = !state.latch;
+                    state.since = now;
+                }
+                state.latch
+            }
+        } && inputs.check(config.and);
+
+        // delay before turning on
+        let value = if value {
+            let since = *state.true_since.get_or_insert(now);
+            reached(now, since.wrapping_add(millis(config.delay)))
+        } else {
+            state.true_since = None;
+            false
+        };
+
+        // minimum duration once on
+        if value && !state.on && config.duration > 0 {
+            state.on_until = Some(now.wrapping_add(millis(config.duration)));
+        }
+        match state.on_until {
+            Some(until) if !reached(now, until) => true,
+            _ => {
+                state.on_until = None;
+                value
+            }
+        }
+    }
+}
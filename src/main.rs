@@ -1,10 +1,6 @@
 #![no_main]
 #![no_std]
 
-mod hid;
-mod inputs;
-mod types;
-
 #[cfg(feature = "rtt")]
 mod panic_rtt {
     use core::panic::PanicInfo;
@@ -21,7 +17,7 @@ use panic_halt as _;
 
 #[rtic::app(device = stm32f4xx_hal::stm32, dispatchers = [SDIO], peripherals = true)]
 mod app {
-    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use dwt_systick_monotonic::{fugit, DwtSystick, ExtU32};
 
     use stm32f4xx_hal::{
         adc::{
@@ -29,24 +25,75 @@ mod app {
             Adc,
         },
         dma::{config::DmaConfig, PeripheralToMemory, Stream0, StreamsTuple, Transfer},
-        gpio::{EPin, Input, PullUp},
+        gpio::{EPin, Edge, ExtiPin, Input, Output, PinState, PullUp, PushPull},
         otg_fs::{UsbBusType, USB},
-        pac::DMA2,
+        pac::{DMA2, USART1},
         prelude::*,
-        stm32::{ADC1, EXTI},
+        serial::{self, Serial, Tx},
+        stm32::ADC1,
+    };
+    #[cfg(feature = "angle-sensors")]
+    use stm32f4xx_hal::{
+        gpio::{
+            gpiob::{PB13, PB14, PB15},
+            Alternate,
+        },
+        pac::SPI2,
+        spi::{Spi, TransferModeNormal},
     };
     use usb_device::{bus::UsbBusAllocator, prelude::*};
 
     #[cfg(feature = "rtt")]
     use rtt_target::{rprint, rprintln, rtt_init_print};
 
-    use crate::{hid::*, inputs::LinearInput, types::JoystickState};
+    #[cfg(feature = "angle-sensors")]
+    use rusty_rc::angle_sensor::{AngleInput, As5048};
+    use rusty_rc::{
+        buzzer::{Beep, Buzzer},
+        codec::{Decode, Reader},
+        config::{self, Config, UsbIdentity, ANALOG_PINS, DIGITAL_PINS, ENCODERS},
+        crsf::{self, Sender},
+        encoder::{Encoder, EncoderOutput},
+        failsafe::{Failsafe, Watchdog},
+        flight_modes::FLIGHT_MODES,
+        gestures::Gestures,
+        hid::*,
+        inputs::LinearInput,
+        mixer::{Inputs, CHANNELS, MAX_MIXES},
+        models::{Model, Models, Protocol, Request, MAX_MODELS},
+        slow::{Follower, Times},
+        special_functions::Action,
+        storage::{SettingsSector, Storage},
+        throttle::{State, Throttle},
+        timers::TimerEvent,
+        trims::{self, TrimEvent, TrimMode, TrimSwitch, TRIMS},
+        types::{
+            ButtonState, JoystickState, Value, REPORT_AXES, STATUS_FAILSAFE, STATUS_RECORDING,
+            VALUE_MAX,
+        },
+    };
 
     const MONO_HZ: u32 = 84_000_000; // 8 MHz
     const REPORT_PERIOD: u32 = 84_000;
-    const ANALOG_PINS: usize = 6;
-    const DIGITAL_PINS: usize = 10;
     const EP_MEMORY_WORDS: usize = 1024;
+    /// The gimbal axes are the first inputs, read from angle sensors instead of the ADC
+    #[cfg(feature = "angle-sensors")]
+    const ANGLE_SENSORS: usize = 4;
+    #[cfg(not(feature = "angle-sensors"))]
+    const ANGLE_SENSORS: usize = 0;
+    /// Time the trims have to be untouched before the settings are saved in milliseconds
+    const SETTINGS_SAVE_DELAY: u32 = 2000;
+    /// Analog inputs which spring back to their center, the others like the throttle keep the
+    /// middle of their travel as center
+    const CENTERING_INPUTS: usize = 2;
+    /// Time between frames to the CRSF module in milliseconds
+    const CRSF_PERIOD: u32 = 4;
+    /// Time between repetitions of the flight mode to the CRSF module in milliseconds
+    const CRSF_FLIGHT_MODE_PERIOD: u32 = 1000;
+    /// Time without analog conversions or valid angles until the failsafe, in milliseconds
+    const ADC_TIMEOUT: u32 = 20;
+    /// Time between checks whether a HID report is due, in milliseconds
+    const USB_REPORT_PERIOD: u32 = 1;
 
     type RcUsbDevice = UsbDevice<'static, UsbBusType>;
     type RcUsbClass = HIDClass<'static, UsbBusType>;
@@ -54,6 +101,20 @@ mod app {
     #[monotonic(binds = SysTick, default = true)]
     type MyMono = DwtSystick<MONO_HZ>;
 
+    #[cfg(feature = "angle-sensors")]
+    type AngleSensorSpi =
+        Spi<SPI2, (PB13<Alternate<5>>, PB14<Alternate<5>>, PB15<Alternate<5>>), TransferModeNormal>;
+    #[cfg(feature = "angle-sensors")]
+    type GimbalSensor = AngleInput<As5048<EPin<Output<PushPull>>>>;
+    /// Bus and sensors of the gimbals, nothing without the angle sensors
+    #[cfg(feature = "angle-sensors")]
+    type AngleSensors = (AngleSensorSpi, [GimbalSensor; ANGLE_SENSORS]);
+    #[cfg(not(feature = "angle-sensors"))]
+    type AngleSensors = ();
+
+    /// A and B channel of a rotary encoder
+    type EncoderPins = (EPin<Input<PullUp>>, EPin<Input<PullUp>>);
+
     type DMATransfer =
         Transfer<Stream0<DMA2>, Adc<ADC1>, PeripheralToMemory, &'static mut [u16; ANALOG_PINS], 0>;
 
@@ -62,21 +123,41 @@ mod app {
         transfer: DMATransfer,
         usb_device: RcUsbDevice,
         usb_class: RcUsbClass,
-        exti: EXTI,
         user_button: EPin<Input<PullUp>>,
         analog_inputs: [u16; ANALOG_PINS],
         digital_inputs: [EPin<Input<PullUp>>; DIGITAL_PINS],
+        encoders: [Encoder; ENCODERS],
+        /// A conversion of the analog inputs completed since the last read
+        adc_fresh: bool,
+        /// Analog inputs whose angle sensor failed at its last read, one bit per input
+        failed_inputs: u8,
+        joystick_state: JoystickState,
+        crsf: Sender<Tx<USART1>>,
+        models: Models,
+        #[lock_free]
+        config: Config,
     }
 
     #[local]
     struct Local {
         buffer: Option<&'static mut [u16; ANALOG_PINS]>,
+        storage: Storage<SettingsSector>,
+        buzzer: Buzzer<EPin<Output<PushPull>>>,
+        led: EPin<Output<PushPull>>,
+        /// The active model
+        model: Model,
         linear_inputs: [LinearInput; ANALOG_PINS],
+        /// Centers of the angle sensors as last saved
+        angle_centers: [Option<u16>; ANALOG_PINS],
+        angle_sensors: AngleSensors,
+        encoder_pins: [EncoderPins; ENCODERS],
+        encoder_outputs: [EncoderOutput; ENCODERS],
+        trim_switches: [TrimSwitch; TRIMS],
         //ep_memory: &'static [u32; 1024],
         //usb_bus: &'static UsbBusAllocator<UsbBusType>
     }
 
-    #[init(local= [ep_memory:[u32; EP_MEMORY_WORDS]= [0; EP_MEMORY_WORDS], usb_bus:Option<UsbBusAllocator<UsbBusType>> = None ])]
+    #[init(local= [ep_memory:[u32; EP_MEMORY_WORDS]= [0; EP_MEMORY_WORDS], usb_bus:Option<UsbBusAllocator<UsbBusType>> = None, usb_identity: Option<UsbIdentity> = None ])]
     fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
         #[cfg(feature = "rtt")]
         rtt_init_print!();
@@ -89,7 +170,7 @@ mod app {
         let rcc = cx.device.RCC.constrain();
         let gpioa = cx.device.GPIOA.split();
         let gpiob = cx.device.GPIOB.split();
-        let _gpioc = cx.device.GPIOC.split();
+        let gpioc = cx.device.GPIOC.split();
 
         // digital inputs
         let user_button = gpioa.pa0.into_pull_up_input().erase();
@@ -148,6 +229,63 @@ mod app {
             .require_pll48clk()
             .freeze();
 
+        // CRSF module, the signal has to be inverted externally for modules expecting that
+        let crsf_tx = Serial::tx(
+            cx.device.USART1,
+            gpioa.pa9.into_alternate(),
+            serial::config::Config::default().baudrate(crsf::BAUDRATE.bps()),
+            _clocks,
+        )
+        .unwrap();
+        let crsf = Sender::new(crsf_tx);
+
+        // persistent settings
+        let storage = Storage::new(SettingsSector::new(cx.device.FLASH));
+        let (config, mut models) = storage.load().map_or_else(Default::default, config::load);
+        // holding the user button and input `i` at power up selects model `i`
+        if user_button.is_low() {
+            if let Some(i) = digital_inputs
+                .iter()
+                .take(MAX_MODELS)
+                .position(|pin| pin.is_low())
+            {
+                models.set_active(i);
+            }
+        }
+        let model = models.model(models.active());
+
+        // angle sensors of the gimbals, relative to the centers stored with the calibration
+        #[cfg(feature = "angle-sensors")]
+        let angle_sensor_spi = Spi::new(
+            cx.device.SPI2,
+            (
+                gpiob.pb13.into_alternate(),
+                gpiob.pb14.into_alternate(),
+                gpiob.pb15.into_alternate(),
+            ),
+            embedded_hal::spi::MODE_1,
+            1.mhz(),
+            _clocks,
+        );
+        #[cfg(feature = "angle-sensors")]
+        let angle_sensors = {
+            let sensor = |cs, i: usize| AngleInput::new(As5048::new(cs), config.angle_centers[i]);
+            [
+                sensor(gpiob.pb12.into_push_pull_output().erase(), 0),
+                sensor(gpioa.pa8.into_push_pull_output().erase(), 1),
+                sensor(gpioc.pc14.into_push_pull_output().erase(), 2),
+                sensor(gpioa.pa10.into_push_pull_output().erase(), 3),
+            ]
+        };
+        #[cfg(feature = "angle-sensors")]
+        let angle_sensors = (angle_sensor_spi, angle_sensors);
+        #[cfg(not(feature = "angle-sensors"))]
+        let angle_sensors = ();
+
+        let linear_inputs = config.calibration;
+        let angle_centers = config.angle_centers;
+        let encoder_outputs = config.encoders.map(EncoderOutput::new);
+
         //// USB initialization
         let usb = USB {
             hclk: 1000.hz(),
@@ -162,25 +300,49 @@ mod app {
 
         // let usb_bus = USB_BUS.as_ref().unwrap();
 
-        let usb_class = HIDClass::new(&cx.local.usb_bus.as_ref().unwrap());
-        // https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt
-        // For USB Joystick as there is no USB Game Pad on this free ID list
+        let usb_class = HIDClass::new(cx.local.usb_bus.as_ref().unwrap(), model.hid_layout);
+        // the descriptors refer to the strings for as long as the device runs
+        let usb_identity = cx.local.usb_identity.insert(config.usb.clone());
         let usb_device = UsbDeviceBuilder::new(
-            &cx.local.usb_bus.as_ref().unwrap(),
-            UsbVidPid(0x16c0, 0x27dc),
+            cx.local.usb_bus.as_ref().unwrap(),
+            UsbVidPid(usb_identity.vid, usb_identity.pid),
         )
-        .manufacturer("autumnal.de")
-        .product("RC USB Controller")
+        .manufacturer(&usb_identity.manufacturer)
+        .product(&usb_identity.product)
         .serial_number(env!("CARGO_PKG_VERSION"))
         .build();
 
         //Initialize Interrupt Input
-        let syscfg = cx.device.SYSCFG;
-        let exti = cx.device.EXTI;
+        let mut syscfg = cx.device.SYSCFG.constrain();
+        let mut exti = cx.device.EXTI;
+
+        // rotary encoders, decoded on every edge of their channels
+        let mut encoder_pins = [(
+            gpiob.pb10.into_pull_up_input().erase(),
+            gpioa.pa15.into_pull_up_input().erase(),
+        )];
+        let mut encoders = [Encoder::new(4)];
+        for ((a, b), encoder) in encoder_pins.iter_mut().zip(encoders.iter_mut()) {
+            for pin in [&mut *a, &mut *b] {
+                pin.make_interrupt_source(&mut syscfg);
+                pin.trigger_on_edge(&mut exti, Edge::RisingFalling);
+                pin.enable_interrupt(&mut exti);
+            }
+            encoder.update_pins(a.is_low(), b.is_low());
+        }
+
+        let buzzer = Buzzer::new(gpioa.pa7.into_push_pull_output().erase());
+        // on board LED, active low
+        let led = gpioc
+            .pc13
+            .into_push_pull_output_in_state(PinState::High)
+            .erase();
 
         // enqueu
         read_analog::spawn().unwrap();
-        //report::spawn().unwrap();
+        #[cfg(feature = "angle-sensors")]
+        read_angles::spawn().unwrap();
+        usb_report::spawn().unwrap();
         polling::spawn().unwrap();
 
         let mono = DwtSystick::new(&mut dcb, dwt, systick, MONO_HZ);
@@ -194,13 +356,28 @@ mod app {
                 usb_device,
                 usb_class,
                 analog_inputs: [1500; ANALOG_PINS],
-                exti,
                 user_button,
                 digital_inputs,
+                encoders,
+                adc_fresh: false,
+                failed_inputs: 0,
+                joystick_state: JoystickState::default(),
+                crsf,
+                models,
+                config,
             },
             Local {
-                linear_inputs: Default::default(),
+                linear_inputs,
+                angle_centers,
                 buffer: second_buffer,
+                storage,
+                buzzer,
+                led,
+                model,
+                trim_switches: Default::default(),
+                angle_sensors,
+                encoder_pins,
+                encoder_outputs,
             },
             init::Monotonics(mono),
         )
@@ -219,58 +396,654 @@ mod app {
     }
 
     // read analog
-    #[task(shared = [ user_button, digital_inputs, analog_inputs], local = [ linear_inputs])]
+    #[task(
+        shared = [
+            user_button,
+            digital_inputs,
+            analog_inputs,
+            encoders,
+            adc_fresh,
+            failed_inputs,
+            joystick_state,
+            crsf,
+            models,
+            usb_class,
+            config,
+        ],
+        local = [
+            linear_inputs,
+            angle_centers,
+            encoder_outputs,
+            buzzer,
+            led,
+            model,
+            trim_switches,
+            channels: [Value; CHANNELS] = [0; CHANNELS],
+            channel_followers: [Follower; CHANNELS] = [Follower::new(); CHANNELS],
+            mix_followers: [[Follower; MAX_MIXES]; FLIGHT_MODES] =
+                [[Follower::new(); MAX_MIXES]; FLIGHT_MODES],
+            adc_watchdog: Watchdog = Watchdog::new(ADC_TIMEOUT),
+            sensor_watchdog: Watchdog = Watchdog::new(ADC_TIMEOUT),
+            failsafe: Failsafe = Failsafe::new(),
+            throttle: Throttle = Throttle::new(),
+            gestures: Gestures = Gestures::new(),
+            now: u32 = 0,
+            cycles: u32 = 0,
+            settings_changed_at: Option<u32> = None,
+            save_pending: bool = false,
+            crsf_flight_mode_at: u32 = 0,
+            crsf_command: Option<crsf::Frame> = None,
+            model_combo: i8 = 0,
+            /// The user button is held
+            calibrating: bool = false,
+            /// An input was pressed while the user button is held
+            combo_held: bool = false,
+        ]
+    )]
     fn read_analog(cx: read_analog::Context) {
         let read_analog::Context { mut shared, local } = cx;
 
-        let (axes, buttons) = (
+        // milliseconds since boot, the cycle counter wraps too fast to be used directly
+        let cycles = monotonics::now().ticks();
+        let elapsed = cycles.wrapping_sub(*local.cycles) / (MONO_HZ / 1000);
+        *local.cycles = local.cycles.wrapping_add(elapsed * (MONO_HZ / 1000));
+        *local.now = local.now.wrapping_add(elapsed);
+        let now = *local.now;
+
+        let (readings, axes, buttons, user_button, commit_calibration) = (
             shared.user_button,
             shared.analog_inputs,
             shared.digital_inputs,
         )
             .lock(|user_button, analog_inputs, digital_inputs| {
+                // digital
+                let mut buttons = [false; DIGITAL_PINS];
+                digital_inputs
+                    .iter()
+                    .zip(buttons.iter_mut())
+                    .for_each(|(pin, out)| *out = pin.is_low());
+
+                // the user button alone centers the inputs once it is released, together with an
+                // input it selects a model instead
+                let user_button = user_button.is_low();
+                let commit_calibration = *local.calibrating && !user_button && !*local.combo_held;
+                *local.combo_held = user_button && (*local.combo_held || buttons.contains(&true));
+                *local.calibrating = user_button;
+
                 // analog
                 let mut axes = [0u16; ANALOG_PINS];
-                let commit_calibration = user_button.is_low();
-                for (analog_reading, (linear_input, axis)) in analog_inputs
+                for (i, (analog_reading, (linear_input, axis))) in analog_inputs
                     .iter()
                     .zip(local.linear_inputs.iter_mut().zip(axes.iter_mut()))
-                    .take(2)
+                    .enumerate()
                 {
-                    if commit_calibration {
+                    if commit_calibration && i < CENTERING_INPUTS {
                         linear_input.set_center(*analog_reading);
                     }
                     *axis = linear_input.get(*analog_reading);
                 }
-
-                // digital
-                let mut buttons = [false; DIGITAL_PINS];
-                digital_inputs
-                    .iter()
-                    .zip(buttons.iter_mut())
-                    .for_each(|(pin, out)| *out = pin.is_low());
-                (axes, buttons)
+                (
+                    *analog_inputs,
+                    axes,
+                    buttons,
+                    user_button,
+                    commit_calibration,
+                )
             });
 
-        // print the readings
-        #[cfg(feature = "rtt")]
+        if commit_calibration {
+            shared.config.calibration = *local.linear_inputs;
+            *local.settings_changed_at = Some(now.wrapping_sub(SETTINGS_SAVE_DELAY));
+        }
+        // a center taken by an angle sensor, the calibration is relative to it
+        if shared.config.angle_centers != *local.angle_centers {
+            *local.angle_centers = shared.config.angle_centers;
+            *local.settings_changed_at = Some(now.wrapping_sub(SETTINGS_SAVE_DELAY));
+        }
+
+        // configuration written by the host, the USB identity takes effect after a reset
+        if let Some(config) = shared.usb_class.lock(|class| class.take_config_request()) {
+            *local.linear_inputs = config.calibration;
+            *local.angle_centers = config.angle_centers;
+            *local.encoder_outputs = config.encoders.map(EncoderOutput::new);
+            *shared.config = config;
+            *local.save_pending = true;
+        }
+        let linear_inputs = &*local.linear_inputs;
+        shared
+            .usb_class
+            .lock(|class| class.set_analog(&readings, linear_inputs));
+
+        // model memories, the user button together with input 0 or 1 selects the next or previous
+        // model
+        let combo = match (user_button, buttons[0], buttons[1]) {
+            (true, true, false) => 1,
+            (true, false, true) => -1,
+            _ => 0,
+        };
+        let mut request = shared.usb_class.lock(|class| class.take_model_request());
+        if combo != 0 && combo != *local.model_combo {
+            let active = shared.models.lock(|models| models.active());
+            let next = (active as i8 + combo).rem_euclid(MAX_MODELS as i8);
+            request = Some(Request::Select(next as u8));
+        }
+        *local.model_combo = combo;
+        let mut new_model = None;
+        if let Some(request) = request {
+            let model = &*local.model;
+            let result = shared.models.lock(|models| models.handle(request, model));
+            match result {
+                Ok(new) => {
+                    new_model = new;
+                    *local.settings_changed_at = None;
+                    *local.save_pending = true;
+                }
+                #[cfg(feature = "rtt")]
+                Err(e) => rprintln!("model request {:?} failed: {:?}", request, e),
+                #[cfg(not(feature = "rtt"))]
+                Err(_) => {}
+            }
+        }
+        // model written by the host, replacing the active one
+        let written = shared.usb_class.lock(|class| {
+            let mut r = Reader::new(class.take_model_data()?);
+            Model::decode(&mut r).ok().filter(|_| r.is_done())
+        });
+        if let Some(model) = written {
+            shared.models.lock(|models| models.store(&model)).ok();
+            new_model = Some(model);
+            *local.settings_changed_at = None;
+            *local.save_pending = true;
+        }
+        if let Some(new) = new_model {
+            *local.model = new;
+            for follower in local.mix_followers.iter_mut().flatten() {
+                *follower = Follower::new();
+            }
+            local.throttle.reset();
+            local.buzzer.beep(Beep::Long);
+        }
+        let model = &*local.model;
+        shared.usb_class.lock(|class| {
+            if class.take_model_read_request() {
+                class.set_model(model);
+            }
+        });
+
+        // a new HID layout takes effect once it is saved
+        let layout = local.model.hid_layout;
+        if shared.usb_class.lock(|class| class.set_layout(layout)) {
+            let model = &*local.model;
+            shared.models.lock(|models| models.store(model)).ok();
+            *local.save_pending = true;
+        }
+
+        // encoders
+        let mut detents = [0; ENCODERS];
+        shared.encoders.lock(|encoders| {
+            for (encoder, detents) in encoders.iter_mut().zip(detents.iter_mut()) {
+                *detents = encoder.take_detents();
+            }
+        });
+        for (output, detents) in local.encoder_outputs.iter_mut().zip(detents.iter()) {
+            output.update(*detents);
+        }
+
+        // trims and global variables of the active flight mode
+        let mode = local.model.flight_modes.active();
+        let old_trims = local.model.trims;
+        let mut globals_changed = false;
+        let mut trim_events = [None; TRIMS + ENCODERS];
+        for (i, config) in shared.config.trims.iter().enumerate() {
+            let state = ButtonState::from_inputs(buttons[config.pins.0], buttons[config.pins.1]);
+            match config.mode {
+                TrimMode::Offset => {
+                    let steps = local.trim_switches[i].update(state, now);
+                    trim_events[i] = trims::apply(&mut local.model.trims[mode][i], steps, config);
+                }
+                TrimMode::Global(index) => {
+                    let steps = local.trim_switches[i].update(state, now);
+                    let delta = steps * config.step as i32;
+                    globals_changed |= local.model.globals.adjust(mode, index as usize, delta);
+                }
+                TrimMode::Buttons { .. } => {}
+            }
+        }
+        for output in local.encoder_outputs.iter_mut() {
+            if let Some((index, delta)) = output.take_global_delta() {
+                globals_changed |= local.model.globals.adjust(mode, index, delta);
+            }
+        }
+        if let Some((mode, index, value)) =
+            shared.usb_class.lock(|class| class.take_global_request())
+        {
+            globals_changed |= local
+                .model
+                .globals
+                .set(mode as usize, index as usize, value);
+        }
+        for (output, event) in local
+            .encoder_outputs
+            .iter_mut()
+            .zip(trim_events[TRIMS..].iter_mut())
+        {
+            if let Some((axis, steps)) = output.take_trim_steps() {
+                *event = trims::apply(
+                    &mut local.model.trims[mode][axis],
+                    steps,
+                    &shared.config.trims[axis],
+                );
+            }
+        }
+        for (i, event) in trim_events.iter().enumerate() {
+            match event {
+                Some(TrimEvent::Center) => {
+                    if let Some(switch) = local.trim_switches.get_mut(i) {
+                        switch.pause(now);
+                    }
+                    local.buzzer.beep(Beep::Short);
+                }
+                Some(TrimEvent::Limit) => local.buzzer.beep(Beep::Long),
+                None => {}
+            }
+        }
+        local.buzzer.update(now);
+
+        // save the settings once the trims and global variables are left alone
+        if local.model.trims != old_trims || globals_changed {
+            *local.settings_changed_at = Some(now);
+        }
+        if let Some(changed_at) = *local.settings_changed_at {
+            if now.wrapping_sub(changed_at) >= SETTINGS_SAVE_DELAY {
+                let model = &*local.model;
+                shared.models.lock(|models| models.store(model)).ok();
+                *local.save_pending = true;
+                *local.settings_changed_at = None;
+            }
+        }
+
+        // centered axes
+        let mut axes = axes.map(|axis| axis as Value - LinearInput::CENTER as Value);
+        if let Some(stick_mode) = shared.config.stick_mode {
+            stick_mode.apply(&mut axes);
+        }
+        for output in local.encoder_outputs.iter() {
+            if let Some((axis, v)) = output.axis() {
+                axes[axis] = v as Value;
+            }
+        }
+        // trims and trimmed axes of a flight mode
+        let all_trims = local.model.trims;
+        let trimmed = |mode: usize| {
+            let trims = all_trims[mode].map(Value::from);
+            let mut axes = axes;
+            for (axis, trim) in axes.iter_mut().zip(trims.iter()) {
+                *axis = num::clamp(*axis + *trim, -VALUE_MAX, VALUE_MAX);
+            }
+            (axes, trims)
+        };
+        let all_globals = local.model.globals.clone();
+
+        // logical switches and flight mode, based on the channels of the previous frame
+        let previous_channels = *local.channels;
+        let (active_axes, active_trims) = trimmed(mode);
+        let active_globals = all_globals.get(mode);
+        let logical = local.model.logical_switches.states();
+        let gestures = local
+            .gestures
+            .update(&shared.config.gestures, &buttons, now);
+        let inputs = Inputs {
+            axes: &active_axes,
+            trims: &active_trims,
+            inputs: &buttons,
+            logical: &logical,
+            gestures: &gestures,
+            channels: &previous_channels,
+            flight_mode: mode as u8,
+            globals: &active_globals,
+            now,
+            heli: &[],
+        };
+        let active_heli = local
+            .model
+            .heli
+            .evaluate(&inputs, local.model.mixer.curves());
+        let inputs = Inputs {
+            heli: &active_heli,
+            ..inputs
+        };
+        local.model.logical_switches.evaluate(&inputs, now);
+        let logical = local.model.logical_switches.states();
+        let inputs = Inputs {
+            logical: &logical,
+            ..inputs
+        };
+        let flight_mode_changed = local.model.flight_modes.update(&inputs, now);
+        let inputs = Inputs {
+            flight_mode: local.model.flight_modes.active() as u8,
+            ..inputs
+        };
+
+        // special functions, the lasting ones are applied to the outputs and the report
+        let effects = local.model.special_functions.evaluate(&inputs, now);
+        for action in effects.fired.iter() {
+            match *action {
+                Action::ResetTimer(timer) => local.model.timers.reset(timer as usize),
+                Action::Beep(beep) => local.buzzer.beep(beep),
+                Action::CenterTrims => {
+                    local.model.trims[inputs.flight_mode as usize] = [0; TRIMS];
+                    *local.settings_changed_at = Some(now);
+                }
+                Action::Crsf {
+                    realm,
+                    command,
+                    value,
+                } => *local.crsf_command = Some(crsf::command(realm, command, &[value])),
+                _ => {}
+            }
+        }
+
+        // mixer, evaluated for every flight mode which is still fading
+        let heli = &local.model.heli;
+        let mixer = &local.model.mixer;
+        let followers = &mut *local.mix_followers;
+        *local.channels = local.model.flight_modes.blend(|mode| {
+            let (axes, trims) = trimmed(mode);
+            let globals = all_globals.get(mode);
+            let inputs = Inputs {
+                axes: &axes,
+                trims: &trims,
+                flight_mode: mode as u8,
+                globals: &globals,
+                ..inputs
+            };
+            let heli = heli.evaluate(&inputs, mixer.curves());
+            let inputs = Inputs {
+                heli: &heli,
+                ..inputs
+            };
+            let mut channels = previous_channels;
+            mixer.evaluate(&inputs, &mut followers[mode], &mut channels);
+            channels
+        });
+
+        // output limits, the same for every output
+        let mut outputs = [0; CHANNELS];
+        for (output, (channel, limit)) in outputs
+            .iter_mut()
+            .zip(local.channels.iter().zip(local.model.limits.iter()))
+        {
+            *output = limit.apply(*channel);
+        }
+        for ((output, follower), slow) in outputs
+            .iter_mut()
+            .zip(local.channel_followers.iter_mut())
+            .zip(local.model.slow.iter())
+        {
+            if *slow != Times::NONE {
+                *output = follower.update(*output, Times::NONE, *slow, now);
+            }
+        }
+        for (output, value) in outputs.iter_mut().zip(effects.overrides.iter()) {
+            if let Some(value) = value {
+                *output = *value;
+            }
+        }
+
+        // throttle interlocks, warn while the startup check is pending
+        let throttle_config = local.model.throttle;
+        match local.throttle.update(&throttle_config, &inputs) {
+            Some(State::Armed) => local.buzzer.beep(Beep::Short),
+            Some(State::Disarmed) => local.buzzer.beep(Beep::Long),
+            _ => {}
+        }
+        local
+            .throttle
+            .apply(&throttle_config, &inputs, &mut outputs);
+        let led = match local.throttle.state() {
+            State::Startup => {
+                if now % 1000 < elapsed {
+                    local.buzzer.beep(Beep::Long);
+                }
+                now % 500 < 250
+            }
+            State::Disarmed => false,
+            State::Armed => true,
+        };
+        local.led.set_state((!led).into());
+
+        // saving stalls the CPU and with it the outputs, never while the model might be flying
+        if *local.save_pending
+            && local.model.may_save(local.throttle)
+            && save_settings::spawn().is_ok()
         {
+            *local.save_pending = false;
+        }
+
+        // timers, a persistent timer is saved once it stops
+        let timer_events = local.model.timers.update(&inputs, elapsed);
+        for (event, config) in timer_events.iter().zip(local.model.timers.config.iter()) {
+            match event {
+                Some(TimerEvent::Minute) | Some(TimerEvent::Countdown) => {
+                    local.buzzer.beep(Beep::Short)
+                }
+                Some(TimerEvent::End) => local.buzzer.beep(Beep::Long),
+                Some(TimerEvent::Stopped) if config.persistent => {
+                    *local.settings_changed_at = Some(now)
+                }
+                _ => {}
+            }
+        }
+        let timers = &local.model.timers;
+        shared.usb_class.lock(|class| class.set_timers(timers));
+
+        // failsafe once the analog inputs stall or an angle sensor keeps failing
+        if shared.adc_fresh.lock(core::mem::take) {
+            local.adc_watchdog.feed(now);
+        }
+        let failed_inputs = shared.failed_inputs.lock(|failed| *failed);
+        if failed_inputs == 0 {
+            local.sensor_watchdog.feed(now);
+        }
+        let lost = local.adc_watchdog.is_lost(now) || local.sensor_watchdog.is_lost(now);
+        let outputs = local.failsafe.apply(&local.model.failsafe, lost, &outputs);
+
+        // build the report
+        let mut report_outputs = outputs;
+        local.model.orders.hid.apply(&mut report_outputs);
+        let mapped = local.model.hid.evaluate(&inputs, now);
+        let mut report_axes = [0; REPORT_AXES];
+        for ((axis, output), switch) in report_axes
+            .iter_mut()
+            .zip(report_outputs.iter())
+            .zip(mapped.axes.iter())
+        {
+            let output = num::clamp(output.unwrap_or(0), -VALUE_MAX, VALUE_MAX);
+            *axis = switch.unwrap_or(output);
+        }
+        let mut report_buttons = mapped.buttons;
+        for config in shared.config.trims.iter() {
+            if let TrimMode::Buttons {
+                up: up_button,
+                down: down_button,
+            } = config.mode
+            {
+                let (up, down) = config.pins;
+                report_buttons |= (buttons[up] as u128) << up_button;
+                report_buttons |= (buttons[down] as u128) << down_button;
+            }
+        }
+        for output in local.encoder_outputs.iter() {
+            if let Some(button) = output.button() {
+                report_buttons |= 1 << button;
+            }
+        }
+        report_buttons |= effects.buttons;
+        let mut status = 0;
+        if local.failsafe.is_active() {
+            status |= STATUS_FAILSAFE;
+        }
+        if effects.recording {
+            status |= STATUS_RECORDING;
+        }
+        let report = JoystickState::new(report_axes, report_buttons, mapped.hats, status);
+        let model = shared.models.lock(|models| models.active()) as u8;
+        let mode = local.model.flight_modes.active() as u8;
+        let throttle = local.throttle.state() as u8;
+        shared
+            .usb_class
+            .lock(|class| class.set_status(status, model, mode, throttle, failed_inputs));
+        shared.joystick_state.lock(|state| *state = report);
+
+        // CRSF module, commands and the flight mode take the place of the channels when due
+        if flight_mode_changed {
+            *local.crsf_flight_mode_at = now.wrapping_sub(CRSF_FLIGHT_MODE_PERIOD);
+        }
+        if local.model.protocol == Protocol::Crsf && now % CRSF_PERIOD < elapsed {
+            // every frame carries all channels, a channel without pulses stops the channel frames
+            let mut channels = [0; CHANNELS];
+            let pulses = outputs
+                .iter()
+                .zip(channels.iter_mut())
+                .all(|(output, channel)| output.map(|output| *channel = output).is_some());
+            local.model.orders.crsf.apply(&mut channels);
+            let frame = if let Some(command) = local.crsf_command.take() {
+                Some(command)
+            } else if now.wrapping_sub(*local.crsf_flight_mode_at) >= CRSF_FLIGHT_MODE_PERIOD {
+                *local.crsf_flight_mode_at = now;
+                Some(crsf::flight_mode(local.model.flight_modes.name()))
+            } else {
+                pulses.then(|| crsf::rc_channels(&channels))
+            };
+            if let Some(frame) = frame {
+                shared.crsf.lock(|crsf| {
+                    if crsf.send(frame) {
+                        crsf.tx().listen();
+                    }
+                });
+            }
+        }
+
+        // print the readings once per second
+        #[cfg(feature = "rtt")]
+        if now % 1000 < elapsed {
             rprint!("axes: ");
-            for axis in axes.iter().take(2) {
+            for axis in report_axes.iter() {
                 rprint!("[{:4}] ", axis);
             }
             rprint!(", buttons: ");
             for button in buttons.iter() {
                 rprint!("[{}] ", if *button { 'X' } else { ' ' });
             }
-            rprintln!("");
+            rprintln!(
+                ", model: {}, flight mode: {}{}",
+                local.model.name,
+                local.model.flight_modes.name(),
+                if local.failsafe.is_active() {
+                    ", FAILSAFE"
+                } else {
+                    ""
+                }
+            );
         }
 
         // reschedule self
-        read_analog::spawn_after(1.secs()).unwrap();
+        read_analog::spawn_after(fugit::TimerDurationU32::from_ticks(REPORT_PERIOD)).unwrap();
+    }
+
+    // Persist the settings, may stall for seconds if the flash sector needs to be erased, only
+    // spawned while the throttle is not armed or the CRSF module is off
+    #[task(shared = [models, config, usb_class], local = [storage])]
+    fn save_settings(cx: save_settings::Context) {
+        let save_settings::Context { mut shared, local } = cx;
+        let config = &*shared.config;
+        let result = shared
+            .models
+            .lock(|models| config::save(config, models, local.storage));
+
+        // the HID layout of the active model is saved, enumerate again with it
+        if result.is_ok() && shared.usb_class.lock(|class| class.layout_changed()) {
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+
+        #[cfg(feature = "rtt")]
+        if let Err(e) = result {
+            rprintln!("saving settings failed: {:?}", e);
+        }
+        #[cfg(not(feature = "rtt"))]
+        result.ok();
+    }
+
+    // Decode the rotary encoders on every edge
+    #[task(binds = EXTI15_10, shared = [encoders], local = [encoder_pins], priority = 2)]
+    fn encoder_edge(cx: encoder_edge::Context) {
+        let encoder_edge::Context { mut shared, local } = cx;
+        shared.encoders.lock(|encoders| {
+            for ((a, b), encoder) in local.encoder_pins.iter_mut().zip(encoders.iter_mut()) {
+                if a.check_interrupt() || b.check_interrupt() {
+                    a.clear_interrupt_pending_bit();
+                    b.clear_interrupt_pending_bit();
+                    encoder.update_pins(a.is_low(), b.is_low());
+                }
+            }
+        });
     }
 
-    #[task(binds = DMA2_STREAM0, shared = [transfer, analog_inputs], local = [buffer])]
+    // Feed the UART of the CRSF module until the frame is sent
+    #[task(binds = USART1, shared = [crsf], priority = 2)]
+    fn crsf_tx(mut cx: crsf_tx::Context) {
+        cx.shared.crsf.lock(|crsf| {
+            crsf.poll();
+            if !crsf.is_busy() {
+                crsf.tx().unlisten();
+            }
+        });
+    }
+
+    // only spawned with the angle sensors
+    #[task(shared = [analog_inputs, failed_inputs, config], local = [angle_sensors])]
+    fn read_angles(cx: read_angles::Context) {
+        #[cfg(not(feature = "angle-sensors"))]
+        let _ = cx;
+        #[cfg(feature = "angle-sensors")]
+        {
+            let read_angles::Context { shared, local } = cx;
+            let (spi, sensors) = local.angle_sensors;
+
+            // the centers of the configuration, a missing one is taken from the first reading and
+            // saved by `read_analog`
+            let centers = shared.config.angle_centers.iter_mut();
+            let mut angles = [None; ANGLE_SENSORS];
+            for ((sensor, center), angle) in sensors.iter_mut().zip(centers).zip(angles.iter_mut())
+            {
+                sensor.set_center(*center);
+                match sensor.read(spi) {
+                    Ok(v) => *angle = Some(v),
+                    #[cfg(feature = "rtt")]
+                    Err(e) => rprintln!("angle sensor: {:?}", e),
+                    #[cfg(not(feature = "rtt"))]
+                    Err(_) => {}
+                }
+                *center = sensor.center();
+            }
+
+            // a failed input keeps its last valid reading, the failsafe takes over if it persists
+            (shared.analog_inputs, shared.failed_inputs).lock(|analog_inputs, failed| {
+                *failed = 0;
+                for (i, (input, angle)) in analog_inputs.iter_mut().zip(angles.iter()).enumerate() {
+                    match angle {
+                        Some(angle) => *input = *angle,
+                        None => *failed |= 1 << i,
+                    }
+                }
+            });
+
+            // reschedule self
+            read_angles::spawn_after(1.millis()).ok();
+        }
+    }
+
+    #[task(binds = DMA2_STREAM0, shared = [transfer, analog_inputs, adc_fresh], local = [buffer])]
     fn dma(cx: dma::Context) {
         let dma::Context { mut shared, local } = cx;
         let (buffer, _) = shared.transfer.lock(|transfer| {
@@ -279,16 +1052,23 @@ mod app {
                 .unwrap()
         });
 
-        shared.analog_inputs.lock(|a| *a = *buffer);
+        // the gimbal axes might be provided by the angle sensors
+        shared.analog_inputs.lock(|a| {
+            a.iter_mut()
+                .zip(buffer.iter())
+                .skip(ANGLE_SENSORS)
+                .for_each(|(a, b)| *a = *b)
+        });
+        shared.adc_fresh.lock(|fresh| *fresh = true);
 
         *local.buffer = Some(buffer);
     }
 
-    // Periodic status update to Computer (every millisecond)
-    #[task(shared = [usb_class])]
+    // Status updates to the computer, sent on changes and at the idle rate
+    #[task(shared = [usb_class, joystick_state])]
     fn usb_report(mut cx: usb_report::Context) {
         // schedule itself to keep the loop running
-        usb_report::spawn_after(1.millis()).unwrap();
+        usb_report::spawn_after(USB_REPORT_PERIOD.millis()).unwrap();
         // TODO make schedule usb_report from DMA
 
         /*
@@ -301,11 +1081,11 @@ mod app {
         });
         */
 
-        let report = JoystickState::from_ppm_time();
+        let report = cx.shared.joystick_state.lock(|state| *state);
 
         cx.shared
             .usb_class
-            .lock(|class| class.write(unsafe { report.as_u8_slice() }));
+            .lock(|class| class.write(&report, USB_REPORT_PERIOD));
     }
 
     // Global USB Interrupt (does not include Wakeup)
@@ -0,0 +1,393 @@
+//! Mixer between the inputs and the output channels
+//!
+//! Each output channel is computed from its mix lines, in the order in which they are stored.
+//! Everything is fixed point, so the result only depends on the inputs of the frame and the
+//! channels of the previous frame.
+use heapless::Vec;
+
+use crate::curves::{Curve, PointCurve, MAX_CURVES};
+use crate::globals::{Param, GLOBAL_LIMIT};
+use crate::slow::{Follower, Times};
+use crate::types::{Value, VALUE_MAX};
+
+/// Number of output channels
+pub const CHANNELS: usize = 16;
+/// Maximum number of mix lines over all channels
+pub const MAX_MIXES: usize = 64;
+
+/// Something providing a value
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Source {
+    /// Calibrated and trimmed axis
+    Axis(u8),
+    /// Trim of an axis
+    Trim(u8),
+    /// Digital input, `VALUE_MAX` if active and `-VALUE_MAX` otherwise
+    Input(u8),
+    /// Always `VALUE_MAX`
+    Max,
+    /// Output channel, of the previous frame if it is not computed yet
+    Channel(u8),
+    /// Swashplate servo or throttle of the heli mixing
+    Heli(u8),
+}
+
+/// Something which is either on or off
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Switch {
+    /// Digital input
+    Input(u8),
+    /// Logical switch
+    Logical(u8),
+    /// On while the flight mode is active
+    FlightMode(u8),
+    /// Virtual button of a gesture
+    Gesture(u8),
+}
+
+/// Condition for a mix line to be active
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Condition {
+    Always,
+    On(Switch),
+    Off(Switch),
+}
+
+/// How a mix line is combined with the lines before it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MixMode {
+    Add,
+    Multiply,
+    Replace,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MixLine {
+    /// Output channel
+    pub channel: u8,
+    pub source: Source,
+    /// Weight in percent
+    pub weight: Param,
+    /// Offset in percent, added after the weight
+    pub offset: Param,
+    pub curve: Curve,
+    pub condition: Condition,
+    pub mode: MixMode,
+    /// Flight modes the line is used in, one bit per mode
+    pub flight_modes: u16,
+    /// Delay before the line follows its value
+    pub delay: Times,
+    /// Time the line takes for the full travel
+    pub slow: Times,
+}
+
+impl MixLine {
+    /// Line with 100% of `source` on `channel`
+    pub const fn new(channel: u8, source: Source) -> Self {
+        Self {
+            channel,
+            source,
+            weight: Param::Value(100),
+            offset: Param::Value(0),
+            curve: Curve::None,
+            condition: Condition::Always,
+            mode: MixMode::Add,
+            flight_modes: u16::MAX,
+            delay: Times::NONE,
+            slow: Times::NONE,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// No more space for mix lines or curves
+    Full,
+    /// Index or channel out of range
+    OutOfRange,
+}
+
+/// Everything a mix line can refer to, for one frame
+#[derive(Copy, Clone)]
+pub struct Inputs<'a> {
+    /// Calibrated and trimmed axes
+    pub axes: &'a [Value],
+    pub trims: &'a [Value],
+    /// Digital inputs, `true` if active
+    pub inputs: &'a [bool],
+    pub logical: &'a [bool],
+    /// Virtual buttons of the gestures
+    pub gestures: &'a [bool],
+    /// Channels of the previous frame
+    pub channels: &'a [Value],
+    pub flight_mode: u8,
+    /// Global variables of the flight mode
+    pub globals: &'a [i16],
+    /// Milliseconds of the monotonic clock
+    pub now: u32,
+    /// Outputs of the heli mixing of the flight mode
+    pub heli: &'a [Value],
+}
+
+impl Inputs<'_> {
+    /// Value of a source
+    pub fn value(&self, source: Source) -> Value {
+        let get = |values: &[Value], i: u8| values.get(i as usize).copied().unwrap_or(0);
+        match source {
+            Source::Axis(i) => get(self.axes, i),
+            Source::Trim(i) => get(self.trims, i),
+            Source::Input(i) if self.is_on(Switch::Input(i)) => VALUE_MAX,
+            Source::Input(_) => -VALUE_MAX,
+            Source::Max => VALUE_MAX,
+            Source::Channel(i) => get(self.channels, i),
+            Source::Heli(i) => get(self.heli, i),
+        }
+    }
+
+    pub fn is_on(&self, switch: Switch) -> bool {
+        match switch {
+            Switch::Input(i) => self.inputs.get(i as usize).copied().unwrap_or(false),
+            Switch::Logical(i) => self.logical.get(i as usize).copied().unwrap_or(false),
+            Switch::FlightMode(i) => self.flight_mode == i,
+            Switch::Gesture(i) => self.gestures.get(i as usize).copied().unwrap_or(false),
+        }
+    }
+
+    pub fn check(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::Always => true,
+            Condition::On(switch) => self.is_on(switch),
+            Condition::Off(switch) => !self.is_on(switch),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Mixer {
+    /// Mix lines, ordered by channel
+    mixes: Vec<MixLine, MAX_MIXES>,
+    curves: Vec<PointCurve, MAX_CURVES>,
+}
+
+impl Mixer {
+    /// Largest magnitude of a channel, leaves headroom for output limits above 100%
+    pub const CHANNEL_LIMIT: Value = 2 * VALUE_MAX;
+
+    /// Mixer passing the first `axes` axes to the first channels
+    pub fn passthrough(axes: u8) -> Self {
+        let mut mixer = Self::default();
+        for i in 0..axes {
+            mixer.add(MixLine::new(i, Source::Axis(i))).ok();
+        }
+        mixer
+    }
+
+    pub fn mixes(&self) -> &[MixLine] {
+        &self.mixes
+    }
+
+    /// Append a line to the lines of its channel
+    pub fn add(&mut self, mix: MixLine) -> Result<usize, Error> {
+        if mix.channel as usize >= CHANNELS {
+            return Err(Error::OutOfRange);
+        }
+        let index = self
+            .mixes
+            .iter()
+            .position(|m| m.channel > mix.channel)
+            .unwrap_or(self.mixes.len());
+        self.mixes.push(mix).map_err(|_| Error::Full)?;
+        self.mixes[index..].rotate_right(1);
+        Ok(index)
+    }
+
+    /// Replace a line, moving it if the channel changed
+    pub fn set(&mut self, index: usize, mix: MixLine) -> Result<usize, Error> {
+        match self.mixes.get_mut(index) {
+            Some(m) if m.channel == mix.channel => {
+                *m = mix;
+                Ok(index)
+            }
+            Some(_) => {
+                self.remove(index);
+                self.add(mix)
+            }
+            None => Err(Error::OutOfRange),
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<MixLine> {
+        if index >= self.mixes.len() {
+            return None;
+        }
+        self.mixes[index..].rotate_left(1);
+        self.mixes.pop()
+    }
+
+    /// Remove all lines of a channel
+    pub fn clear_channel(&mut self, channel: u8) {
+        while let Some(index) = self.mixes.iter().position(|m| m.channel == channel) {
+            self.remove(index);
+        }
+    }
+
+    pub fn curves(&self) -> &[PointCurve] {
+        &self.curves
+    }
+
+    /// Set a custom curve, curves up to `index` are created if necessary
+    pub fn set_curve(&mut self, index: usize, curve: PointCurve) -> Result<(), Error> {
+        if index >= MAX_CURVES {
+            return Err(Error::OutOfRange);
+        }
+        while self.curves.len() <= index {
+            self.curves.push(PointCurve::default()).ok();
+        }
+        self.curves[index] = curve;
+        Ok(())
+    }
+
+    /// Value of one mix line before it is combined with the others
+    fn line_value(
+        curves: &[PointCurve],
+        mix: &MixLine,
+        inputs: &Inputs,
+        channels: &[Value; CHANNELS],
+    ) -> Value {
+        let v = match mix.source {
+            Source::Channel(i) => channels.get(i as usize).copied().unwrap_or(0),
+            source => inputs.value(source),
+        };
+        let v = mix.curve.apply(v, curves, inputs.globals);
+        let percent = |p: Param| num::clamp(p.get(inputs.globals), -GLOBAL_LIMIT, GLOBAL_LIMIT);
+        let (weight, offset) = (percent(mix.weight), percent(mix.offset));
+        (v as i64 * weight as i64 / 100 + offset as i64 * VALUE_MAX as i64 / 100) as Value
+    }
+
+    /// Compute the channels
+    ///
+    /// `channels` has to contain the channels of the previous frame, they are used where a mix line
+    /// refers to a channel which is not computed yet. Channels without lines are `0`.
+    ///
+    /// `followers` hold the delayed and slowed values of the lines, one per line. Every flight mode
+    /// needs its own while the modes fade, and they start over once the lines change.
+    pub fn evaluate(
+        &self,
+        inputs: &Inputs,
+        followers: &mut [Follower; MAX_MIXES],
+        channels: &mut [Value; CHANNELS],
+    ) {
+        let mut lines = self.mixes.iter().zip(followers.iter_mut()).peekable();
+        for channel in 0..CHANNELS {
+            let mut acc: Value = 0;
+            while let Some((mix, follower)) = lines.next_if(|(m, _)| m.channel as usize == channel)
+            {
+                let in_flight_mode = mix.flight_modes >> inputs.flight_mode & 1 != 0;
+                if !in_flight_mode || !inputs.check(mix.condition) {
+                    continue;
+                }
+                let mut v = Self::line_value(&self.curves, mix, inputs, channels);
+                if mix.delay != Times::NONE || mix.slow != Times::NONE {
+                    v = follower.update(v, mix.delay, mix.slow, inputs.now);
+                }
+                acc = match mix.mode {
+                    MixMode::Add => acc.saturating_add(v),
+                    MixMode::Multiply => {
+                        let product = acc as i64 * v as i64 / VALUE_MAX as i64;
+                        num::clamp(product, Value::MIN as i64, Value::MAX as i64) as Value
+                    }
+                    MixMode::Replace => v,
+                };
+            }
+            channels[channel] = num::clamp(acc, -Self::CHANNEL_LIMIT, Self::CHANNEL_LIMIT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs<'a>(axes: &'a [Value], switches: &'a [bool], globals: &'a [i16]) -> Inputs<'a> {
+        Inputs {
+            axes,
+            trims: &[],
+            inputs: switches,
+            logical: &[],
+            gestures: &[],
+            channels: &[],
+            flight_mode: 0,
+            globals,
+            now: 0,
+            heli: &[],
+        }
+    }
+
+    fn evaluate(mixer: &Mixer, inputs: &Inputs) -> [Value; CHANNELS] {
+        let mut channels = [0; CHANNELS];
+        mixer.evaluate(inputs, &mut [Follower::new(); MAX_MIXES], &mut channels);
+        channels
+    }
+
+    #[test]
+    fn passthrough() {
+        let mixer = Mixer::passthrough(4);
+        let channels = evaluate(&mixer, &inputs(&[100, -200, 300, VALUE_MAX], &[], &[]));
+        assert_eq!(channels[..5], [100, -200, 300, VALUE_MAX, 0]);
+    }
+
+    #[test]
+    fn weight_and_offset() {
+        let mut mixer = Mixer::default();
+        let mut mix = MixLine::new(0, Source::Axis(0));
+        mix.weight = Param::Value(50);
+        mix.offset = Param::Value(10);
+        mixer.add(mix).unwrap();
+        let channels = evaluate(&mixer, &inputs(&[VALUE_MAX], &[], &[]));
+        assert_eq!(channels[0], VALUE_MAX / 2 + VALUE_MAX / 10);
+    }
+
+    #[test]
+    fn modes_and_conditions() {
+        let mut mixer = Mixer::default();
+        mixer.add(MixLine::new(0, Source::Axis(0))).unwrap();
+        let mut multiply = MixLine::new(0, Source::Axis(1));
+        multiply.mode = MixMode::Multiply;
+        mixer.add(multiply).unwrap();
+        let mut replace = MixLine::new(0, Source::Max);
+        replace.mode = MixMode::Replace;
+        replace.condition = Condition::On(Switch::Input(0));
+        mixer.add(replace).unwrap();
+
+        let axes = [VALUE_MAX / 2, VALUE_MAX / 2];
+        let channels = evaluate(&mixer, &inputs(&axes, &[false], &[]));
+        assert_eq!(channels[0], VALUE_MAX / 4);
+        let channels = evaluate(&mixer, &inputs(&axes, &[true], &[]));
+        assert_eq!(channels[0], VALUE_MAX);
+    }
+
+    #[test]
+    fn lines_are_ordered_by_channel() {
+        let mut mixer = Mixer::default();
+        assert_eq!(mixer.add(MixLine::new(2, Source::Max)), Ok(0));
+        assert_eq!(mixer.add(MixLine::new(0, Source::Max)), Ok(0));
+        assert_eq!(mixer.add(MixLine::new(1, Source::Max)), Ok(1));
+        let channels: Vec<u8, 3> = mixer.mixes().iter().map(|m| m.channel).collect();
+        assert_eq!(channels, [0, 1, 2]);
+        assert_eq!(
+            mixer.add(MixLine::new(CHANNELS as u8, Source::Max)),
+            Err(Error::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn large_parameters_are_limited() {
+        let mut mixer = Mixer::default();
+        let mut mix = MixLine::new(0, Source::Axis(0));
+        mix.weight = Param::Global(0);
+        mix.offset = Param::Global(0);
+        mixer.add(mix).unwrap();
+        let channels = evaluate(&mixer, &inputs(&[VALUE_MAX], &[], &[i16::MAX]));
+        assert_eq!(channels[0], Mixer::CHANNEL_LIMIT);
+    }
+}
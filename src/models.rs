@@ -0,0 +1,695 @@
+//! Model memories
+//!
+//! Only the active model is kept decoded, the others are kept as their encoding. All of them are
+//! stored together as one record, so switching models never depends on the flash contents.
+use heapless::{String, Vec};
+
+use crate::codec::{Decode, Encode, Error, Reader, Writer};
+use crate::config::{self, VERSION};
+use crate::failsafe::FailsafeMode;
+use crate::flight_modes::{FlightModes, FLIGHT_MODES};
+use crate::globals::{Globals, GLOBAL_LIMIT};
+use crate::heli::Heli;
+use crate::hid_map::HidMap;
+use crate::limits::Limit;
+use crate::logical_switches::LogicalSwitches;
+use crate::mixer::{Mixer, CHANNELS};
+use crate::report::Layout;
+use crate::slow::Times;
+use crate::special_functions::SpecialFunctions;
+use crate::sticks::ChannelOrders;
+use crate::storage::{Flash, Storage};
+use crate::templates::Template;
+use crate::throttle::{Throttle, ThrottleConfig};
+use crate::timers::{Timers, TIMERS};
+use crate::trims::TRIMS;
+use crate::types::OLD_VALUE_MAX;
+
+/// Number of model memories
+pub const MAX_MODELS: usize = 8;
+/// Largest encoded model, enough for a model using everything up to its bounds
+pub const MODEL_SIZE: usize = 2048;
+/// Longest model name
+pub const NAME_LEN: usize = 12;
+
+/// Largest record of all models
+pub const RECORD_SIZE: usize = 2 + MAX_MODELS * (2 + MODEL_SIZE);
+
+/// Axes passed to the channels by a new model
+const PASSTHROUGH_AXES: u8 = 6;
+
+/// Output to an RF module
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    None,
+    Crsf,
+}
+
+#[derive(Clone, Debug)]
+pub struct Model {
+    pub name: String<NAME_LEN>,
+    pub mixer: Mixer,
+    pub limits: [Limit; CHANNELS],
+    /// Time each channel takes for the full travel
+    pub slow: [Times; CHANNELS],
+    pub failsafe: [FailsafeMode; CHANNELS],
+    pub throttle: ThrottleConfig,
+    pub timers: Timers,
+    pub special_functions: SpecialFunctions,
+    pub globals: Globals,
+    pub heli: Heli,
+    /// Order of the first channels sent to the host and the RF module
+    pub orders: ChannelOrders,
+    /// Switches passed to the host
+    pub hid: HidMap,
+    /// Fields of the HID report, the device enumerates again when it changes
+    pub hid_layout: Layout,
+    pub logical_switches: LogicalSwitches,
+    pub flight_modes: FlightModes,
+    /// Trims of every flight mode
+    pub trims: [[i16; TRIMS]; FLIGHT_MODES],
+    pub protocol: Protocol,
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            mixer: Mixer::passthrough(PASSTHROUGH_AXES),
+            limits: Default::default(),
+            slow: Default::default(),
+            failsafe: Default::default(),
+            throttle: Default::default(),
+            timers: Default::default(),
+            special_functions: Default::default(),
+            globals: Default::default(),
+            heli: Default::default(),
+            orders: Default::default(),
+            hid: Default::default(),
+            hid_layout: Default::default(),
+            logical_switches: Default::default(),
+            flight_modes: Default::default(),
+            trims: Default::default(),
+            protocol: Protocol::Crsf,
+        }
+    }
+}
+
+impl Encode for Model {
+    fn encode(&self, w: &mut Writer) {
+        w.str(&self.name);
+        self.mixer.encode(w);
+        self.limits.encode(w);
+        self.logical_switches.config.encode(w);
+        self.flight_modes.modes.encode(w);
+        self.trims.encode(w);
+        w.u8(match self.protocol {
+            Protocol::None => 0,
+            Protocol::Crsf => 1,
+        });
+        self.failsafe.encode(w);
+        self.throttle.encode(w);
+        self.timers.config.encode(w);
+        for elapsed in self.timers.elapsed().iter() {
+            w.u32(*elapsed);
+        }
+        self.special_functions.config.encode(w);
+        self.globals.values.encode(w);
+        for per_mode in self.globals.per_mode.iter() {
+            w.bool(*per_mode);
+        }
+        self.slow.encode(w);
+        self.heli.encode(w);
+        self.orders.encode(w);
+        self.hid.config.encode(w);
+        self.hid_layout.encode(w);
+    }
+}
+
+impl Decode for Model {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Self::decode_version(r, VERSION)
+    }
+}
+
+impl Model {
+    /// Whether saving, which stalls the outputs, is safe: the RF module is off or the motor is not
+    /// armed
+    pub fn may_save(&self, throttle: &Throttle) -> bool {
+        self.protocol != Protocol::Crsf || !throttle.motor_armed(&self.throttle)
+    }
+
+    /// Decode a model of a settings record of `version`
+    pub fn decode_version(r: &mut Reader, version: u8) -> Result<Self, Error> {
+        let mut model = Self {
+            name: r.str()?,
+            mixer: Mixer::decode_version(r, version)?,
+            limits: Decode::decode(r)?,
+            ..Default::default()
+        };
+        model.logical_switches.config = Decode::decode(r)?;
+        model.flight_modes.modes = Decode::decode(r)?;
+        model.trims = Decode::decode(r)?;
+        if version < 14 {
+            for trim in model.trims.iter_mut().flatten() {
+                *trim = config::rescale(*trim);
+            }
+        }
+        model.protocol = match r.u8()? {
+            0 => Protocol::None,
+            1 => Protocol::Crsf,
+            _ => return Err(Error::Invalid),
+        };
+        if version >= 3 {
+            model.failsafe = Decode::decode(r)?;
+        }
+        if version >= 4 {
+            model.throttle = Decode::decode(r)?;
+        }
+        if version >= 5 {
+            model.timers.config = Decode::decode(r)?;
+            let mut elapsed = [0; TIMERS];
+            for elapsed in elapsed.iter_mut() {
+                *elapsed = r.u32()?;
+            }
+            model.timers.restore(elapsed);
+        }
+        if version >= 6 {
+            model.special_functions.config = Decode::decode(r)?;
+        }
+        if version >= 7 {
+            model.globals.values = Decode::decode(r)?;
+            let range = -GLOBAL_LIMIT..=GLOBAL_LIMIT;
+            if !model
+                .globals
+                .values
+                .iter()
+                .flatten()
+                .all(|v| range.contains(v))
+            {
+                return Err(Error::Invalid);
+            }
+            for per_mode in model.globals.per_mode.iter_mut() {
+                *per_mode = r.bool()?;
+            }
+        }
+        if version >= 8 {
+            model.slow = Decode::decode(r)?;
+        }
+        if version >= 9 {
+            model.heli = Decode::decode(r)?;
+        }
+        if version >= 10 {
+            model.orders = Decode::decode(r)?;
+        }
+        if version >= 11 {
+            model.hid.config = Decode::decode(r)?;
+        }
+        if version >= 13 {
+            model.hid_layout = Decode::decode(r)?;
+            // the default layout of version 13 used the range of the old values, keep the
+            // default for the higher resolution
+            let old_default = Layout {
+                axis_max: OLD_VALUE_MAX,
+                ..Layout::JOYSTICK
+            };
+            if version < 14 && model.hid_layout == old_default {
+                model.hid_layout = Layout::JOYSTICK;
+            }
+        }
+        Ok(model)
+    }
+}
+
+/// Operations on the model memories
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Request {
+    Select(u8),
+    Copy {
+        from: u8,
+        to: u8,
+    },
+    Reset(u8),
+    /// Replace a model by a new one of a template
+    Create {
+        index: u8,
+        template: Template,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct Models {
+    /// Encoded models, empty for unused memories
+    images: [Vec<u8, MODEL_SIZE>; MAX_MODELS],
+    active: usize,
+}
+
+impl Models {
+    /// Models written by [`Models::save`] of a settings record of `version`, without the prefix
+    pub fn load(payload: &[u8], version: u8) -> Result<Self, Error> {
+        let mut r = Reader::new(payload);
+        let mut models = Self {
+            active: r.index(MAX_MODELS)? as usize,
+            ..Default::default()
+        };
+        for image in models.images.iter_mut().take(r.count(MAX_MODELS)?) {
+            let len = r.u16()? as usize;
+            image
+                .extend_from_slice(r.bytes(len)?)
+                .map_err(|_| Error::Invalid)?;
+        }
+        if !r.is_done() {
+            return Err(Error::Invalid);
+        }
+
+        // bring the models up to date, the invalid ones are lost anyway
+        if version < VERSION {
+            for i in 0..MAX_MODELS {
+                let mut r = Reader::new(&models.images[i]);
+                match Model::decode_version(&mut r, version) {
+                    Ok(model) if r.is_done() => models.write(i, &model)?,
+                    _ => models.images[i].clear(),
+                }
+            }
+        }
+        Ok(models)
+    }
+
+    /// Write all models as one record, following `prefix`
+    pub fn save<F: Flash>(&self, storage: &mut Storage<F>, prefix: &[u8]) -> Result<(), F::Error> {
+        let header = [self.active as u8, MAX_MODELS as u8];
+        let mut lens = [[0; 2]; MAX_MODELS];
+        for (len, image) in lens.iter_mut().zip(self.images.iter()) {
+            *len = (image.len() as u16).to_le_bytes();
+        }
+        let mut parts = Vec::<&[u8], { 2 + 2 * MAX_MODELS }>::new();
+        parts.push(prefix).ok();
+        parts.push(&header).ok();
+        for (len, image) in lens.iter().zip(self.images.iter()) {
+            parts.push(len).ok();
+            parts.push(image).ok();
+        }
+        storage.save(&parts)
+    }
+
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Make another model active without storing the current one, e.g. at boot
+    pub fn set_active(&mut self, index: usize) {
+        if index < MAX_MODELS {
+            self.active = index;
+        }
+    }
+
+    /// Decoded model, a new one if the memory is unused or its contents are invalid
+    pub fn model(&self, index: usize) -> Model {
+        match self.images.get(index) {
+            Some(image) if !image.is_empty() => {
+                let mut r = Reader::new(image);
+                Model::decode(&mut r)
+                    .ok()
+                    .filter(|_| r.is_done())
+                    .unwrap_or_default()
+            }
+            _ => Model::default(),
+        }
+    }
+
+    fn write(&mut self, index: usize, model: &Model) -> Result<(), Error> {
+        let mut buf = [0; MODEL_SIZE];
+        let mut w = Writer::new(&mut buf);
+        model.encode(&mut w);
+        let len = w.finish()?;
+        let image = &mut self.images[index];
+        image.clear();
+        image.extend_from_slice(&buf[..len]).ok();
+        Ok(())
+    }
+
+    /// Change every stored model, e.g. when settings of the device move to the models
+    pub fn update_all(&mut self, f: impl Fn(&mut Model)) -> Result<(), Error> {
+        for i in 0..MAX_MODELS {
+            if !self.images[i].is_empty() {
+                let mut model = self.model(i);
+                f(&mut model);
+                self.write(i, &model)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the active model, e.g. after its trims changed
+    pub fn store(&mut self, model: &Model) -> Result<(), Error> {
+        self.write(self.active, model)
+    }
+
+    /// Handle a request, `current` is the active model
+    ///
+    /// Returns the new active model if it changed.
+    pub fn handle(&mut self, request: Request, current: &Model) -> Result<Option<Model>, Error> {
+        let check = |index: u8| match index as usize {
+            i if i < MAX_MODELS => Ok(i),
+            _ => Err(Error::Invalid),
+        };
+        self.store(current)?;
+        match request {
+            Request::Select(index) => {
+                self.active = check(index)?;
+                Ok(Some(self.model(self.active)))
+            }
+            Request::Copy { from, to } => {
+                self.images[check(to)?] = self.images[check(from)?].clone();
+                Ok((to as usize == self.active).then(|| self.model(self.active)))
+            }
+            Request::Reset(index) => {
+                self.images[check(index)?].clear();
+                Ok((index as usize == self.active).then(Model::default))
+            }
+            Request::Create { index, template } => {
+                let model = template.model();
+                self.write(check(index)?, &model)?;
+                Ok((index as usize == self.active).then_some(model))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::heli::Swash;
+    use crate::hid_map::HidMapping;
+    use crate::mixer::{Inputs, Source, Switch};
+    use crate::sticks::ChannelOrder;
+    use crate::throttle::State;
+    use crate::timers::{TimerConfig, Trigger};
+    use crate::types::VALUE_MAX;
+
+    pub fn encoding(model: &Model) -> std::vec::Vec<u8> {
+        let mut buf = [0; MODEL_SIZE];
+        let mut w = Writer::new(&mut buf);
+        model.encode(&mut w);
+        let len = w.finish().unwrap();
+        buf[..len].to_vec()
+    }
+
+    /// Model using the fields of every version
+    pub fn full() -> Model {
+        let mut model = Template::Flaperons.model();
+        model.name.push_str("Glider").unwrap();
+        let mut mix = model.mixer.mixes()[0];
+        mix.delay = Times { up: 100, down: 200 };
+        model.mixer.set(0, mix).unwrap();
+        model.trims[1] = [0, 10, -20, 30].map(config::rescale);
+        model.protocol = Protocol::None;
+        model.failsafe[2] = FailsafeMode::Custom(-1000);
+        model.throttle.channel = Some(2);
+        model.timers.config[0] = TimerConfig {
+            trigger: Trigger::Above(Source::Axis(2), 5),
+            start: 300,
+            minute_beep: true,
+            persistent: true,
+        };
+        model.timers.restore([61_000, 0, 0]);
+        model.globals.values[1][0] = 42;
+        model.globals.per_mode[0] = true;
+        model.slow[4] = Times { up: 500, down: 0 };
+        model.heli.swash = Some(Swash::H120);
+        model.orders.crsf = ChannelOrder([2, 0, 1, 3]);
+        model.hid.config[7] = Some(HidMapping::button(Switch::Input(3), 9));
+        model.hid_layout.axis_bits = 12;
+        model.hid_layout.axis_max = 2047;
+        model
+    }
+
+    /// Trim or axis value as a settings record before version 14 stored it
+    fn unscale(v: i16) -> i16 {
+        (v as f32 * OLD_VALUE_MAX as f32 / VALUE_MAX as f32).round() as i16
+    }
+
+    /// Encoding of `model` by the firmware writing settings records of `version`
+    pub fn encode_version(model: &Model, version: u8) -> std::vec::Vec<u8> {
+        let mut buf = [0; MODEL_SIZE];
+        let mut w = Writer::new(&mut buf);
+        w.str(&model.name);
+        w.u8(model.mixer.mixes().len() as u8);
+        for mix in model.mixer.mixes() {
+            let mut line = [0; 64];
+            let mut lw = Writer::new(&mut line);
+            mix.encode(&mut lw);
+            let len = lw.finish().unwrap();
+            // the delay and slow times at the end came with version 8
+            w.bytes(&line[..if version < 8 { len - 8 } else { len }]);
+        }
+        w.u8(model.mixer.curves().len() as u8);
+        for curve in model.mixer.curves() {
+            w.u8(curve.points.len() as u8);
+            for point in curve.points.iter() {
+                point.encode(&mut w);
+            }
+        }
+        model.limits.encode(&mut w);
+        model.logical_switches.config.encode(&mut w);
+        model.flight_modes.modes.encode(&mut w);
+        for trim in model.trims.iter().flatten() {
+            w.i16(if version < 14 { unscale(*trim) } else { *trim });
+        }
+        w.u8(model.protocol as u8);
+        if version >= 3 {
+            model.failsafe.encode(&mut w);
+        }
+        if version >= 4 {
+            model.throttle.encode(&mut w);
+        }
+        if version >= 5 {
+            model.timers.config.encode(&mut w);
+            for elapsed in model.timers.elapsed().iter() {
+                w.u32(*elapsed);
+            }
+        }
+        if version >= 6 {
+            model.special_functions.config.encode(&mut w);
+        }
+        if version >= 7 {
+            model.globals.values.encode(&mut w);
+            for per_mode in model.globals.per_mode.iter() {
+                w.bool(*per_mode);
+            }
+        }
+        if version >= 8 {
+            model.slow.encode(&mut w);
+        }
+        if version >= 9 {
+            model.heli.encode(&mut w);
+        }
+        if version >= 10 {
+            model.orders.encode(&mut w);
+        }
+        if version >= 11 {
+            model.hid.config.encode(&mut w);
+        }
+        if version >= 13 {
+            model.hid_layout.encode(&mut w);
+        }
+        let len = w.finish().unwrap();
+        buf[..len].to_vec()
+    }
+
+    /// `model` as a settings record of `version` brings it back, the newer fields are new
+    fn migrated(model: &Model, version: u8) -> Model {
+        let mut expected = Model {
+            name: model.name.clone(),
+            mixer: model.mixer.clone(),
+            limits: model.limits,
+            logical_switches: model.logical_switches.clone(),
+            flight_modes: model.flight_modes.clone(),
+            trims: model.trims,
+            protocol: model.protocol,
+            ..Default::default()
+        };
+        if version < 8 {
+            let mut mix = expected.mixer.mixes()[0];
+            mix.delay = Times::default();
+            expected.mixer.set(0, mix).unwrap();
+        }
+        if version >= 3 {
+            expected.failsafe = model.failsafe;
+        }
+        if version >= 4 {
+            expected.throttle = model.throttle;
+        }
+        if version >= 5 {
+            expected.timers = model.timers.clone();
+        }
+        if version >= 6 {
+            expected.special_functions.config = model.special_functions.config;
+        }
+        if version >= 7 {
+            expected.globals = model.globals.clone();
+        }
+        if version >= 8 {
+            expected.slow = model.slow;
+        }
+        if version >= 9 {
+            expected.heli = model.heli;
+        }
+        if version >= 10 {
+            expected.orders = model.orders;
+        }
+        if version >= 11 {
+            expected.hid.config = model.hid.config;
+        }
+        if version >= 13 {
+            expected.hid_layout = model.hid_layout;
+        }
+        expected
+    }
+
+    #[test]
+    fn saving() {
+        let inputs = Inputs {
+            axes: &[0; 4],
+            trims: &[],
+            inputs: &[],
+            logical: &[],
+            gestures: &[],
+            channels: &[],
+            flight_mode: 0,
+            globals: &[],
+            now: 0,
+            heli: &[],
+        };
+        // without a throttle channel the throttle counts as armed, but no motor runs
+        let mut model = Model::default();
+        let mut throttle = Throttle::new();
+        throttle.update(&model.throttle, &inputs);
+        assert_eq!(throttle.state(), State::Armed);
+        assert!(model.may_save(&throttle));
+
+        model.throttle.channel = Some(2);
+        model.throttle.stick = Source::Axis(0);
+        model.throttle.low = 0;
+        throttle.reset();
+        throttle.update(&model.throttle, &inputs);
+        assert_eq!(throttle.state(), State::Armed);
+        assert!(!model.may_save(&throttle));
+        model.protocol = Protocol::None;
+        assert!(model.may_save(&throttle));
+    }
+
+    #[test]
+    fn out_of_range_globals() {
+        let mut model = Model::default();
+        model.globals.values[3][1] = -GLOBAL_LIMIT;
+        let bytes = encoding(&model);
+        assert_eq!(
+            Model::decode(&mut Reader::new(&bytes)).unwrap().globals,
+            model.globals
+        );
+        for value in [GLOBAL_LIMIT + 1, i16::MIN].iter() {
+            model.globals.values[3][1] = *value;
+            let bytes = encoding(&model);
+            assert_eq!(
+                Model::decode(&mut Reader::new(&bytes)).unwrap_err(),
+                Error::Invalid
+            );
+        }
+    }
+
+    #[test]
+    fn model_round_trip() {
+        let bytes = encoding(&full());
+        assert_eq!(bytes, encode_version(&full(), VERSION));
+        let mut r = Reader::new(&bytes);
+        let model = Model::decode(&mut r).unwrap();
+        assert!(r.is_done());
+        assert_eq!(encoding(&model), bytes);
+    }
+
+    #[test]
+    fn models_of_every_version() {
+        for version in 2..=VERSION {
+            let bytes = encode_version(&full(), version);
+            let mut r = Reader::new(&bytes);
+            let model = Model::decode_version(&mut r, version).unwrap();
+            assert!(r.is_done(), "version {}", version);
+            assert_eq!(
+                encoding(&model),
+                encoding(&migrated(&full(), version)),
+                "version {}",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn default_layout_of_version_13() {
+        let old_default = Layout {
+            axis_max: OLD_VALUE_MAX,
+            ..Layout::JOYSTICK
+        };
+        for (version, layout, expected) in [
+            (13, old_default, Layout::JOYSTICK),
+            (13, full().hid_layout, full().hid_layout),
+            (14, old_default, old_default),
+        ]
+        .iter()
+        {
+            let model = Model {
+                hid_layout: *layout,
+                ..Default::default()
+            };
+            let bytes = encode_version(&model, *version);
+            let model = Model::decode_version(&mut Reader::new(&bytes), *version).unwrap();
+            assert_eq!(model.hid_layout, *expected, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn memories_of_every_version() {
+        for version in 2..=VERSION {
+            let model = encode_version(&full(), version);
+            let mut payload = vec![1, MAX_MODELS as u8];
+            for i in 0..MAX_MODELS {
+                let image: &[u8] = match i {
+                    1 => &model,
+                    // a broken model is reset, the others are kept
+                    2 => &model[..model.len() - 1],
+                    _ => &[],
+                };
+                payload.extend_from_slice(&(image.len() as u16).to_le_bytes());
+                payload.extend_from_slice(image);
+            }
+            let models = Models::load(&payload, version).unwrap();
+            assert_eq!(models.active(), 1);
+            assert_eq!(
+                encoding(&models.model(1)),
+                encoding(&migrated(&full(), version))
+            );
+            assert_eq!(encoding(&models.model(2)), encoding(&Model::default()));
+        }
+    }
+
+    #[test]
+    fn requests() {
+        let mut models = Models::default();
+        let current = full();
+        let created = Request::Create {
+            index: 3,
+            template: Template::Plane,
+        };
+        assert!(models.handle(created, &current).unwrap().is_none());
+        assert_eq!(encoding(&models.model(0)), encoding(&current));
+        let selected = models.handle(Request::Select(3), &current).unwrap();
+        assert_eq!(models.active(), 3);
+        assert_eq!(
+            encoding(&selected.unwrap()),
+            encoding(&Template::Plane.model())
+        );
+        let copied = models.handle(Request::Copy { from: 0, to: 3 }, &current);
+        assert_eq!(encoding(&copied.unwrap().unwrap()), encoding(&current));
+        assert!(models.handle(Request::Reset(8), &current).is_err());
+    }
+}
@@ -0,0 +1,688 @@
+//! Layout of the HID input report
+//!
+//! The report descriptor and the serialization of [`JoystickState`] are both generated from a
+//! [`Layout`], so they cannot disagree. Fields are packed in the order of the descriptor, least
+//! significant bit first, as HID expects them. The layout is chosen per model, the device
+//! enumerates with the one of the model active at boot.
+//!
+//! The descriptor also declares the vendor defined feature and output reports of a second
+//! collection, which a host tool reaches through the standard HID driver. The output reports are
+//! commands, the feature reports are read and some of them written. As they need report IDs, the
+//! input report starts with one as well.
+use crate::config::{ANALOG_PINS, CONFIG_SIZE, HID_BUTTONS};
+use crate::models::MODEL_SIZE;
+use crate::timers::TIMERS;
+use crate::types::{JoystickState, REPORT_AXES, REPORT_HATS, VALUE_MAX};
+
+/// Largest report descriptor, it has to fit the control buffer of the USB stack
+pub const MAX_DESCRIPTOR: usize = 256;
+/// Largest input report, in bytes
+pub const MAX_REPORT: usize = 64;
+
+/// ID of the input report
+pub const INPUT_REPORT_ID: u8 = 0x01;
+
+// Output and feature reports, their lengths include the ID
+/// Output report managing the model memories, `[id, operation, index, index or template]`
+pub const MODEL_REPORT_ID: u8 = 0x10;
+const MODEL_REPORT_LEN: usize = 4;
+/// Timers, `[id, running bits, (seconds: i32)*]`
+pub const TIMER_REPORT_ID: u8 = 0x11;
+pub const TIMER_REPORT_LEN: usize = 2 + 4 * TIMERS;
+/// Output report setting a global variable, `[id, flight mode, index, value: i16]`
+pub const GLOBAL_REPORT_ID: u8 = 0x12;
+const GLOBAL_REPORT_LEN: usize = 5;
+/// Raw readings of the analog inputs, `[id, (reading: u16)*]`
+pub const ANALOG_REPORT_ID: u8 = 0x13;
+pub const ANALOG_REPORT_LEN: usize = 1 + 2 * ANALOG_PINS;
+/// Calibration of the analog inputs, `[id, (state, start: u16, mid: u16, end: u16)*]` with the
+/// states of the settings encoding
+pub const CALIBRATION_REPORT_ID: u8 = 0x14;
+pub const CALIBRATION_REPORT_LEN: usize = 1 + 7 * ANALOG_PINS;
+/// Firmware version, `[id, major, minor, patch, settings version]`
+pub const VERSION_REPORT_ID: u8 = 0x15;
+pub const VERSION_REPORT_LEN: usize = 5;
+/// Device status, `[id, status bits, model, flight mode, throttle state, layout changed,
+/// failed analog inputs]`
+pub const STATUS_REPORT_ID: u8 = 0x16;
+pub const STATUS_REPORT_LEN: usize = 7;
+/// Output report writing the device configuration in the settings encoding, a chunk at a time with
+/// `[id, 0, offset: u16, data]`, then apply and save it with `[id, 1, length: u16]`
+pub const CONFIG_REPORT_ID: u8 = 0x17;
+pub const CONFIG_REPORT_LEN: usize = 64;
+/// Data of a chunk of the configuration report
+pub const CONFIG_CHUNK: usize = CONFIG_REPORT_LEN - 4;
+const _: () = assert!(CONFIG_SIZE <= u16::MAX as usize);
+/// Read or write the active model in the settings encoding. Writing sends the chunks with
+/// `[id, 0, offset: u16, data]` and replaces the model with `[id, 1, length: u16, settings
+/// version]`. Reading asks for the model with `[id, 2]`, selects a chunk with `[id, 3, offset:
+/// u16]` and gets `[id, settings version, length: u16, data]`, the version is `0` until the model
+/// is ready. An invalid model is dropped, reading the model back tells whether it was taken.
+pub const MODEL_DATA_REPORT_ID: u8 = 0x18;
+pub const MODEL_DATA_REPORT_LEN: usize = 64;
+/// Data of a chunk of the model data report
+pub const MODEL_CHUNK: usize = MODEL_DATA_REPORT_LEN - 4;
+const _: () = assert!(MODEL_SIZE <= u16::MAX as usize);
+
+/// IDs and lengths of the output reports
+const OUTPUTS: [(u8, usize); 3] = [
+    (MODEL_REPORT_ID, MODEL_REPORT_LEN),
+    (GLOBAL_REPORT_ID, GLOBAL_REPORT_LEN),
+    (CONFIG_REPORT_ID, CONFIG_REPORT_LEN),
+];
+
+/// IDs and lengths of the feature reports
+const FEATURES: [(u8, usize); 6] = [
+    (TIMER_REPORT_ID, TIMER_REPORT_LEN),
+    (ANALOG_REPORT_ID, ANALOG_REPORT_LEN),
+    (CALIBRATION_REPORT_ID, CALIBRATION_REPORT_LEN),
+    (VERSION_REPORT_ID, VERSION_REPORT_LEN),
+    (STATUS_REPORT_ID, STATUS_REPORT_LEN),
+    (MODEL_DATA_REPORT_ID, MODEL_DATA_REPORT_LEN),
+];
+
+// item prefixes without the size bits
+const USAGE_PAGE: u8 = 0x04;
+const USAGE: u8 = 0x08;
+const USAGE_MINIMUM: u8 = 0x18;
+const USAGE_MAXIMUM: u8 = 0x28;
+const LOGICAL_MINIMUM: u8 = 0x14;
+const LOGICAL_MAXIMUM: u8 = 0x24;
+const PHYSICAL_MINIMUM: u8 = 0x34;
+const PHYSICAL_MAXIMUM: u8 = 0x44;
+const UNIT: u8 = 0x64;
+const REPORT_SIZE: u8 = 0x74;
+const REPORT_ID: u8 = 0x84;
+const REPORT_COUNT: u8 = 0x94;
+const INPUT: u8 = 0x80;
+const OUTPUT: u8 = 0x90;
+const FEATURE: u8 = 0xB0;
+const COLLECTION: u8 = 0xA0;
+const END_COLLECTION: u8 = 0xC0;
+
+const GENERIC_DESKTOP: u32 = 0x01;
+const BUTTON: u32 = 0x09;
+const VENDOR: u32 = 0xFF00;
+const JOYSTICK: u32 = 0x04;
+/// Vendor defined usage of the collection of the output and feature reports
+const CONFIGURATION: u32 = 0x02;
+const POINTER: u32 = 0x01;
+const HAT_SWITCH: u32 = 0x39;
+/// Usages of the axes, the sticks first: X, Y, Z, Rx, slider, dial, Ry, Rz
+const AXIS_USAGES: [u32; REPORT_AXES] = [0x30, 0x31, 0x32, 0x33, 0x36, 0x37, 0x34, 0x35];
+/// Axes in the pointer collection
+const STICK_AXES: usize = 4;
+
+// input, output and feature item flags
+const DATA_VAR_ABS: u32 = 0x02;
+const CONST_VAR_ABS: u32 = 0x03;
+const DATA_VAR_ABS_NULL: u32 = 0x42;
+
+/// Report descriptor, built by chaining items
+#[derive(Copy, Clone, Debug)]
+pub struct Descriptor {
+    bytes: [u8; MAX_DESCRIPTOR],
+    len: usize,
+}
+
+impl Descriptor {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; MAX_DESCRIPTOR],
+            len: 0,
+        }
+    }
+
+    const fn push(mut self, byte: u8) -> Self {
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        self
+    }
+
+    /// Item with an unsigned value, as short as possible
+    const fn item(self, prefix: u8, value: u32) -> Self {
+        let size = if value > 0xFFFF {
+            4
+        } else if value > 0xFF {
+            2
+        } else {
+            1
+        };
+        self.data(prefix, value, size)
+    }
+
+    /// Item with a signed value, as short as possible
+    const fn signed(self, prefix: u8, value: i32) -> Self {
+        let size = if value < -0x8000 || value > 0x7FFF {
+            4
+        } else if value < -0x80 || value > 0x7F {
+            2
+        } else {
+            1
+        };
+        self.data(prefix, value as u32, size)
+    }
+
+    const fn data(self, prefix: u8, value: u32, size: usize) -> Self {
+        let code = if size == 4 { 3 } else { size as u8 };
+        let mut this = self.push(prefix | code);
+        let mut i = 0;
+        while i < size {
+            this = this.push((value >> (8 * i)) as u8);
+            i += 1;
+        }
+        this
+    }
+
+    /// Main item without data
+    const fn end_collection(self) -> Self {
+        self.push(END_COLLECTION)
+    }
+
+    /// Reports of bytes with the ID as their usage, `main` being their type
+    const fn vendor_reports(mut self, reports: &[(u8, usize)], main: u8) -> Self {
+        let mut i = 0;
+        while i < reports.len() {
+            let (id, len) = reports[i];
+            self = self
+                .item(REPORT_ID, id as u32)
+                .item(USAGE, id as u32)
+                .item(REPORT_COUNT, len as u32 - 1)
+                .item(main, DATA_VAR_ABS);
+            i += 1;
+        }
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Bits of the input report the descriptor declares, without the report ID
+    pub const fn report_bits(&self) -> usize {
+        let (mut size, mut count, mut bits) = (0, 0, 0);
+        let mut i = 0;
+        while i < self.len {
+            let prefix = self.bytes[i];
+            let len = match prefix & 0x03 {
+                3 => 4,
+                len => len as usize,
+            };
+            let mut value = 0;
+            let mut j = 0;
+            while j < len {
+                value |= (self.bytes[i + 1 + j] as usize) << (8 * j);
+                j += 1;
+            }
+            match prefix & !0x03 {
+                REPORT_SIZE => size = value,
+                REPORT_COUNT => count = value,
+                INPUT => bits += size * count,
+                _ => {}
+            }
+            i += 1 + len;
+        }
+        bits
+    }
+}
+
+/// Fields of the input report, in the order of the report
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Layout {
+    pub axes: u8,
+    /// Bits of an axis
+    pub axis_bits: u8,
+    /// Axes range over `-axis_max..=axis_max`
+    pub axis_max: i32,
+    pub buttons: u8,
+    /// Hat switches, a nibble each
+    pub hats: u8,
+    /// Vendor defined status byte, see `STATUS_*`
+    pub status: bool,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::JOYSTICK
+    }
+}
+
+impl Layout {
+    /// Axes of 16 bits, smaller sizes like 12 bits with a range of `2047` work as well
+    pub const JOYSTICK: Self = Self {
+        axes: 6,
+        axis_bits: 16,
+        axis_max: 0x7FFF,
+        buttons: 16,
+        hats: 1,
+        status: true,
+    };
+
+    /// Whether the fields exist and the axis range fits its bits
+    pub const fn is_valid(&self) -> bool {
+        self.axes as usize <= REPORT_AXES
+            && self.axis_bits >= 8
+            && self.axis_bits <= 16
+            && self.axis_max > 0
+            && self.axis_max < 1 << (self.axis_bits - 1)
+            && self.buttons as usize <= HID_BUTTONS
+            && self.hats as usize <= REPORT_HATS
+    }
+
+    /// Report descriptor of a joystick with the fields, followed by the feature reports
+    pub const fn descriptor(&self) -> Descriptor {
+        let mut d = Descriptor::new()
+            .item(USAGE_PAGE, GENERIC_DESKTOP)
+            .item(USAGE, JOYSTICK)
+            .item(COLLECTION, 0x01) // application
+            .item(REPORT_ID, INPUT_REPORT_ID as u32);
+
+        // axes, the sticks in a physical collection
+        let axes = self.axes as usize;
+        let sticks = if axes < STICK_AXES { axes } else { STICK_AXES };
+        let mut i = 0;
+        while i < axes {
+            if i == 0 && sticks > 0 {
+                d = d
+                    .item(USAGE, POINTER)
+                    .item(COLLECTION, 0x00) // physical
+                    .item(USAGE_PAGE, GENERIC_DESKTOP);
+            }
+            d = d.item(USAGE, AXIS_USAGES[i]);
+            i += 1;
+            if i == sticks || i == axes {
+                let count = if i == sticks { sticks } else { axes - sticks };
+                d = d
+                    .signed(LOGICAL_MINIMUM, -self.axis_max)
+                    .signed(LOGICAL_MAXIMUM, self.axis_max)
+                    .item(REPORT_SIZE, self.axis_bits as u32)
+                    .item(REPORT_COUNT, count as u32)
+                    .item(INPUT, DATA_VAR_ABS);
+                if i == sticks {
+                    d = d.end_collection();
+                }
+            }
+        }
+        // the buttons start at a whole byte
+        let axis_bits = axes * self.axis_bits as usize;
+        if !axis_bits.is_multiple_of(8) {
+            d = d
+                .item(REPORT_SIZE, 8 - axis_bits as u32 % 8)
+                .item(REPORT_COUNT, 1)
+                .item(INPUT, CONST_VAR_ABS);
+        }
+
+        if self.buttons > 0 {
+            d = d
+                .item(USAGE_PAGE, BUTTON)
+                .item(USAGE_MINIMUM, 1)
+                .item(USAGE_MAXIMUM, self.buttons as u32)
+                .signed(LOGICAL_MINIMUM, 0)
+                .signed(LOGICAL_MAXIMUM, 1)
+                .item(REPORT_SIZE, 1)
+                .item(REPORT_COUNT, self.buttons as u32)
+                .item(INPUT, DATA_VAR_ABS);
+            if !self.buttons.is_multiple_of(8) {
+                d = d
+                    .item(REPORT_SIZE, 8 - self.buttons as u32 % 8)
+                    .item(REPORT_COUNT, 1)
+                    .item(INPUT, CONST_VAR_ABS);
+            }
+        }
+
+        if self.hats > 0 {
+            d = d.item(USAGE_PAGE, GENERIC_DESKTOP);
+            let mut i = 0;
+            while i < self.hats {
+                d = d.item(USAGE, HAT_SWITCH);
+                i += 1;
+            }
+            d = d
+                .signed(LOGICAL_MINIMUM, 0)
+                .signed(LOGICAL_MAXIMUM, 7)
+                .signed(PHYSICAL_MINIMUM, 0)
+                .signed(PHYSICAL_MAXIMUM, 315)
+                .item(UNIT, 0x14) // degrees
+                .item(REPORT_SIZE, 4)
+                .item(REPORT_COUNT, self.hats as u32)
+                .item(INPUT, DATA_VAR_ABS_NULL)
+                .item(UNIT, 0x00);
+            if !self.hats.is_multiple_of(2) {
+                d = d
+                    .item(REPORT_SIZE, 4)
+                    .item(REPORT_COUNT, 1)
+                    .item(INPUT, CONST_VAR_ABS);
+            }
+        }
+
+        if self.status {
+            d = d
+                .item(USAGE_PAGE, VENDOR)
+                .item(USAGE, 0x01)
+                .signed(LOGICAL_MINIMUM, 0)
+                .signed(LOGICAL_MAXIMUM, 0xFF)
+                .item(REPORT_SIZE, 8)
+                .item(REPORT_COUNT, 1)
+                .item(INPUT, DATA_VAR_ABS);
+        }
+        d = d.end_collection();
+
+        // output and feature reports of bytes
+        d.item(USAGE_PAGE, VENDOR)
+            .item(USAGE, CONFIGURATION)
+            .item(COLLECTION, 0x01) // application
+            .signed(LOGICAL_MINIMUM, 0)
+            .signed(LOGICAL_MAXIMUM, 0xFF)
+            .item(REPORT_SIZE, 8)
+            .vendor_reports(&OUTPUTS, OUTPUT)
+            .vendor_reports(&FEATURES, FEATURE)
+            .end_collection()
+    }
+
+    /// Bytes of the input report, including its ID
+    pub const fn report_len(&self) -> usize {
+        let bits = (self.axes as usize * self.axis_bits as usize).div_ceil(8) * 8
+            + (self.buttons as usize).div_ceil(8) * 8
+            + (self.hats as usize).div_ceil(2) * 8
+            + 8 * self.status as usize;
+        1 + bits.div_ceil(8)
+    }
+
+    /// Serialize a state into `buf`, returns the length of the report
+    pub fn write(&self, state: &JoystickState, buf: &mut [u8; MAX_REPORT]) -> usize {
+        buf[0] = INPUT_REPORT_ID;
+        let mut w = BitWriter { buf, bit: 8 };
+        for axis in state.axes.iter().take(self.axes as usize) {
+            let axis = num::clamp(*axis, -VALUE_MAX, VALUE_MAX) as i64;
+            let v = axis * self.axis_max as i64 / VALUE_MAX as i64;
+            w.bits(v as u128, self.axis_bits);
+        }
+        w.align();
+        w.bits(state.buttons, self.buttons);
+        w.align();
+        for hat in state.hats.iter().take(self.hats as usize) {
+            w.bits(*hat as u128, 4);
+        }
+        w.align();
+        if self.status {
+            w.bits(state.status as u128, 8);
+        }
+        w.bit.div_ceil(8)
+    }
+}
+
+/// Descriptor of the default layout, checked against the serialized size
+pub const JOYSTICK_DESCRIPTOR: Descriptor = Layout::JOYSTICK.descriptor();
+const _: () = assert!(JOYSTICK_DESCRIPTOR.report_bits() + 8 == 8 * Layout::JOYSTICK.report_len());
+// the largest layout fits as well
+const _: () = {
+    let largest = Layout {
+        axes: REPORT_AXES as u8,
+        axis_bits: 16,
+        axis_max: 0x7FFF,
+        buttons: HID_BUTTONS as u8,
+        hats: REPORT_HATS as u8,
+        status: true,
+    };
+    assert!(largest.is_valid());
+    assert!(largest.report_len() <= MAX_REPORT);
+    assert!(largest.descriptor().report_bits() + 8 == 8 * largest.report_len());
+};
+
+struct BitWriter<'a> {
+    buf: &'a mut [u8; MAX_REPORT],
+    bit: usize,
+}
+
+impl BitWriter<'_> {
+    /// Append the low `n` bits of `value`
+    fn bits(&mut self, value: u128, n: u8) {
+        for i in 0..n as usize {
+            let (byte, bit) = ((self.bit + i) / 8, (self.bit + i) % 8);
+            if let Some(byte) = self.buf.get_mut(byte) {
+                let set = value.checked_shr(i as u32).unwrap_or(0) & 1;
+                *byte = *byte & !(1 << bit) | (set as u8) << bit;
+            }
+        }
+        self.bit += n as usize;
+    }
+
+    /// Pad to the next byte
+    fn align(&mut self) {
+        self.bit = self.bit.div_ceil(8) * 8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Main item of a parsed descriptor
+    #[derive(Clone, Debug)]
+    struct Field {
+        main: u8,
+        flags: u32,
+        report_id: u8,
+        usage_page: u32,
+        /// Usages, or the minimum and maximum of a usage range
+        usages: std::vec::Vec<u32>,
+        logical: (i32, i32),
+        size: usize,
+        count: usize,
+        /// Bit of the report where the field starts, after the report ID
+        bit: usize,
+    }
+
+    /// Main items of a descriptor, tracking the global and local items a host parser does
+    fn parse(descriptor: &[u8]) -> std::vec::Vec<Field> {
+        let mut fields = std::vec::Vec::new();
+        let (mut page, mut logical, mut size, mut count, mut report_id) = (0, (0, 0), 0, 0, 0);
+        let mut usages = std::vec::Vec::new();
+        let mut bits = std::collections::HashMap::new();
+        let mut depth = 0;
+        let mut i = 0;
+        while i < descriptor.len() {
+            let prefix = descriptor[i];
+            let len = [0, 1, 2, 4][(prefix & 0x03) as usize];
+            let data = &descriptor[i + 1..i + 1 + len];
+            let value = data
+                .iter()
+                .rev()
+                .fold(0u32, |value, byte| value << 8 | *byte as u32);
+            // sign extension of the short item
+            let signed = match len {
+                1 => value as u8 as i8 as i32,
+                2 => value as u16 as i16 as i32,
+                _ => value as i32,
+            };
+            match prefix & !0x03 {
+                USAGE_PAGE => page = value,
+                USAGE | USAGE_MINIMUM | USAGE_MAXIMUM => usages.push(value),
+                LOGICAL_MINIMUM => logical.0 = signed,
+                LOGICAL_MAXIMUM => logical.1 = signed,
+                REPORT_SIZE => size = value as usize,
+                REPORT_COUNT => count = value as usize,
+                REPORT_ID => report_id = value as u8,
+                COLLECTION => {
+                    depth += 1;
+                    usages.clear();
+                }
+                END_COLLECTION => depth -= 1,
+                main @ (INPUT | OUTPUT | FEATURE) => {
+                    let bit = bits.entry((main, report_id)).or_insert(0);
+                    fields.push(Field {
+                        main,
+                        flags: value,
+                        report_id,
+                        usage_page: page,
+                        usages: std::mem::take(&mut usages),
+                        logical,
+                        size,
+                        count,
+                        bit: *bit,
+                    });
+                    *bit += size * count;
+                }
+                PHYSICAL_MINIMUM | PHYSICAL_MAXIMUM | UNIT => {}
+                item => panic!("unknown item {:#x}", item),
+            }
+            assert!(depth >= 0, "collection closed twice");
+            i += 1 + len;
+        }
+        assert_eq!(depth, 0, "collection not closed");
+        fields
+    }
+
+    /// Field of the report at `bit`, sign extended
+    fn read(report: &[u8], bit: usize, size: usize) -> i64 {
+        let value = (0..size).fold(0i64, |value, i| {
+            let (byte, shift) = ((bit + i) / 8, (bit + i) % 8);
+            value | ((report[byte] >> shift & 1) as i64) << i
+        });
+        value << (64 - size) >> (64 - size)
+    }
+
+    const LAYOUTS: [Layout; 4] = [
+        Layout::JOYSTICK,
+        Layout {
+            axes: 3,
+            axis_bits: 12,
+            axis_max: 2047,
+            buttons: 5,
+            hats: 3,
+            status: false,
+        },
+        Layout {
+            axes: REPORT_AXES as u8,
+            axis_bits: 10,
+            axis_max: 500,
+            buttons: HID_BUTTONS as u8,
+            hats: 0,
+            status: true,
+        },
+        Layout {
+            axes: 0,
+            axis_bits: 8,
+            axis_max: 127,
+            buttons: 0,
+            hats: REPORT_HATS as u8,
+            status: false,
+        },
+    ];
+
+    #[test]
+    fn descriptor_declares_the_layout() {
+        for layout in LAYOUTS.iter() {
+            assert!(layout.is_valid());
+            let descriptor = layout.descriptor();
+            let fields = parse(descriptor.as_bytes());
+            let inputs: std::vec::Vec<_> = fields.iter().filter(|f| f.main == INPUT).collect();
+            assert!(inputs.iter().all(|f| f.report_id == INPUT_REPORT_ID));
+            let bits: usize = inputs.iter().map(|f| f.size * f.count).sum();
+            assert_eq!(bits, 8 * (layout.report_len() - 1), "{:?}", layout);
+            assert_eq!(bits, descriptor.report_bits());
+
+            let axes: std::vec::Vec<u32> = inputs
+                .iter()
+                .filter(|f| f.usage_page == GENERIC_DESKTOP && f.size == layout.axis_bits as usize)
+                .flat_map(|f| {
+                    assert_eq!(f.logical, (-layout.axis_max, layout.axis_max));
+                    assert_eq!(f.usages.len(), f.count);
+                    f.usages.iter().copied()
+                })
+                .collect();
+            assert_eq!(axes, AXIS_USAGES[..layout.axes as usize]);
+
+            let buttons = inputs.iter().find(|f| f.usage_page == BUTTON);
+            match buttons {
+                Some(f) => {
+                    assert_eq!(f.usages, [1, layout.buttons as u32]);
+                    assert_eq!((f.size, f.count), (1, layout.buttons as usize));
+                }
+                None => assert_eq!(layout.buttons, 0),
+            }
+            let hats: usize = inputs
+                .iter()
+                .filter(|f| f.usages.first() == Some(&HAT_SWITCH))
+                .map(|f| f.count)
+                .sum();
+            assert_eq!(hats, layout.hats as usize);
+        }
+    }
+
+    #[test]
+    fn descriptor_declares_the_vendor_reports() {
+        let fields = parse(Layout::JOYSTICK.descriptor().as_bytes());
+        let reports = |main| -> std::vec::Vec<_> {
+            fields
+                .iter()
+                .filter(|f| f.main == main)
+                .map(|f| {
+                    assert_eq!((f.usage_page, f.size, f.logical), (VENDOR, 8, (0, 0xFF)));
+                    assert_eq!(f.usages, [f.report_id as u32]);
+                    (f.report_id, f.count + 1)
+                })
+                .collect()
+        };
+        let (outputs, features) = (reports(OUTPUT), reports(FEATURE));
+        assert_eq!(outputs, OUTPUTS);
+        assert_eq!(features, FEATURES);
+        for (id, _) in outputs.iter() {
+            assert!(*id != INPUT_REPORT_ID);
+            assert!(features.iter().all(|(feature, _)| feature != id));
+        }
+        assert!(features.iter().all(|(id, _)| *id != INPUT_REPORT_ID));
+    }
+
+    #[test]
+    fn report_matches_the_descriptor() {
+        let state = JoystickState {
+            axes: [
+                VALUE_MAX,
+                -VALUE_MAX,
+                VALUE_MAX / 2,
+                0,
+                1,
+                -2 * VALUE_MAX,
+                3,
+                -4,
+            ],
+            buttons: 0b1_0110 | 1 << 127,
+            hats: [0, 3, 7, 8],
+            status: 0xA5,
+        };
+        for layout in LAYOUTS.iter() {
+            let mut report = [0; MAX_REPORT];
+            assert_eq!(layout.write(&state, &mut report), layout.report_len());
+            assert_eq!(report[0], INPUT_REPORT_ID);
+            let report = &report[1..layout.report_len()];
+            let mut axes = 0;
+            let mut hats = 0;
+            for field in parse(layout.descriptor().as_bytes()) {
+                if field.main != INPUT || field.flags == CONST_VAR_ABS {
+                    continue;
+                }
+                for i in 0..field.count {
+                    let value = read(report, field.bit + i * field.size, field.size);
+                    match (field.usage_page, field.usages.first()) {
+                        (BUTTON, _) => {
+                            assert_eq!(value & 1, (state.buttons >> i & 1) as i64);
+                        }
+                        (VENDOR, _) => assert_eq!(value as u8, state.status),
+                        (_, Some(&HAT_SWITCH)) => {
+                            assert_eq!(value & 0xF, state.hats[hats] as i64);
+                            hats += 1;
+                        }
+                        _ => {
+                            let axis = num::clamp(state.axes[axes], -VALUE_MAX, VALUE_MAX);
+                            let expected = axis as i64 * layout.axis_max as i64 / VALUE_MAX as i64;
+                            assert_eq!(value, expected, "axis {} of {:?}", axes, layout);
+                            axes += 1;
+                        }
+                    }
+                }
+            }
+            assert_eq!((axes, hats), (layout.axes as usize, layout.hats as usize));
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Delayed and slowed movement of mix lines and channels, e.g. for retracts and flaps
+//!
+//! Times are in tenths of a second, the speed is given as the time for the full travel from
+//! `-VALUE_MAX` to `VALUE_MAX`. Movement is computed from the timestamps in milliseconds, so it
+//! does not depend on how often it is updated.
+use crate::types::{Value, VALUE_MAX};
+
+/// Times for increasing and decreasing values, `0` is instant
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Times {
+    pub up: u16,
+    pub down: u16,
+}
+
+impl Times {
+    pub const NONE: Self = Self { up: 0, down: 0 };
+
+    /// Time in milliseconds for moving from `from` to `to`
+    fn millis(self, from: Value, to: Value) -> u32 {
+        let tenths = if to > from { self.up } else { self.down };
+        tenths as u32 * 100
+    }
+}
+
+/// Fixed point fraction of the followed value, for slow movements in short steps
+const SCALE: i64 = 1 << 16;
+
+/// Value following a target with a delay and a limited speed
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Follower {
+    /// Current value, scaled by `SCALE`, `None` before the first update
+    value: Option<i64>,
+    /// Target after the delay
+    target: Value,
+    /// Since when the target differs from the delayed one
+    pending_since: Option<u32>,
+    updated_at: u32,
+}
+
+impl Follower {
+    pub const fn new() -> Self {
+        Self {
+            value: None,
+            target: 0,
+            pending_since: None,
+            updated_at: 0,
+        }
+    }
+
+    /// Follow `target`, changes are taken after `delay` and the value moves at most as fast as
+    /// `slow` allows, `now` is in milliseconds
+    pub fn update(&mut self, target: Value, delay: Times, slow: Times, now: u32) -> Value {
+        let elapsed = now.wrapping_sub(self.updated_at);
+        self.updated_at = now;
+        let value = match self.value {
+            Some(value) => value,
+            // start at the target, e.g. after booting
+            None => {
+                self.target = target;
+                target as i64 * SCALE
+            }
+        };
+
+        if target == self.target {
+            self.pending_since = None;
+        } else {
+            let since = *self.pending_since.get_or_insert(now);
+            if now.wrapping_sub(since) >= delay.millis(self.target, target) {
+                self.target = target;
+                self.pending_since = None;
+            }
+        }
+
+        let target = self.target as i64 * SCALE;
+        let current = (value / SCALE) as Value;
+        let value = match slow.millis(current, self.target) as i64 {
+            0 => target,
+            time => {
+                let step = 2 * VALUE_MAX as i64 * SCALE * elapsed as i64 / time;
+                num::clamp(target, value - step, value + step)
+            }
+        };
+        self.value = Some(value);
+        (value / SCALE) as Value
+    }
+}
@@ -0,0 +1,130 @@
+//! Special functions
+//!
+//! Actions bound to conditions, evaluated once per frame after the logical switches. A function
+//! is active while its condition holds, or toggled by the condition turning on if it is edge
+//! triggered. Lasting actions, e.g. overriding a channel, take effect while the function is
+//! active, the others fire once it turns active and then every `repeat`. Times are in tenths of
+//! a second and measured with the timestamps passed to [`SpecialFunctions::evaluate`].
+use heapless::Vec;
+
+use crate::buzzer::Beep;
+use crate::mixer::{Condition, Inputs, CHANNELS};
+use crate::types::{Value, VALUE_MAX};
+
+/// Number of special functions of a model
+pub const SPECIAL_FUNCTIONS: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    None,
+    ResetTimer(u8),
+    Beep(Beep),
+    /// Output of a channel in tenths of a percent, lasting
+    Override {
+        channel: u8,
+        value: i16,
+    },
+    /// Reset the trims of the active flight mode
+    CenterTrims,
+    /// Tell the host to record the sticks, lasting
+    Record,
+    /// Press a HID button, lasting
+    Button(u8),
+    /// Command to the CRSF module
+    Crsf {
+        realm: u8,
+        command: u8,
+        value: u8,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SpecialFunction {
+    pub condition: Condition,
+    pub action: Action,
+    /// Toggle the function each time the condition turns on
+    pub edge: bool,
+    /// Interval of firing again while active, `0` fires once
+    pub repeat: u16,
+}
+
+impl Default for SpecialFunction {
+    fn default() -> Self {
+        Self {
+            condition: Condition::Always,
+            action: Action::None,
+            edge: false,
+            repeat: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct State {
+    /// Condition of the previous frame
+    condition: bool,
+    active: bool,
+    /// Last time the action fired
+    fired_at: u32,
+}
+
+/// Result of evaluating the special functions
+#[derive(Debug, Default)]
+pub struct Effects {
+    /// Actions which fired this frame
+    pub fired: Vec<Action, SPECIAL_FUNCTIONS>,
+    pub overrides: [Option<Value>; CHANNELS],
+    pub recording: bool,
+    /// Pressed HID buttons, one bit per button
+    pub buttons: u128,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SpecialFunctions {
+    pub config: [SpecialFunction; SPECIAL_FUNCTIONS],
+    state: [State; SPECIAL_FUNCTIONS],
+}
+
+impl SpecialFunctions {
+    /// Evaluate all functions, `now` is in milliseconds
+    pub fn evaluate(&mut self, inputs: &Inputs, now: u32) -> Effects {
+        let mut effects = Effects::default();
+        for (config, state) in self.config.iter().zip(self.state.iter_mut()) {
+            if config.action == Action::None {
+                continue;
+            }
+            let condition = inputs.check(config.condition);
+            let rising = condition && !state.condition;
+            state.condition = condition;
+            let was_active = state.active;
+            state.active = if config.edge {
+                state.active != rising
+            } else {
+                condition
+            };
+            if !state.active {
+                continue;
+            }
+
+            match config.action {
+                Action::Override { channel, value } => {
+                    if let Some(output) = effects.overrides.get_mut(channel as usize) {
+                        *output = Some(value as Value * VALUE_MAX / 1000);
+                    }
+                }
+                Action::Record => effects.recording = true,
+                Action::Button(button) => {
+                    effects.buttons |= 1u128.checked_shl(button as u32).unwrap_or(0)
+                }
+                action => {
+                    let repeat = config.repeat as u32 * 100;
+                    if !was_active || (repeat > 0 && now.wrapping_sub(state.fired_at) >= repeat) {
+                        state.fired_at = now;
+                        effects.fired.push(action).ok();
+                    }
+                }
+            }
+        }
+        effects
+    }
+}
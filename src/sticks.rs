@@ -0,0 +1,90 @@
+//! Stick modes and channel orders
+//!
+//! The stick mode assigns the physical gimbal axes to the aileron, elevator, throttle and rudder
+//! axes (AETR) before mixing. The channel order arranges the first channels for an output, as
+//! receivers expect them.
+
+/// Axes of the gimbals, `[left x, left y, right x, right y]` physically and AETR logically
+pub const STICK_AXES: usize = 4;
+
+/// Physical gimbal axes
+const LEFT_X: usize = 0;
+const LEFT_Y: usize = 1;
+const RIGHT_X: usize = 2;
+const RIGHT_Y: usize = 3;
+
+/// Assignment of the gimbal axes, the throttle of a gimbal without spring centering is on the left
+/// in modes 2 and 4 and on the right in modes 1 and 3
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StickMode {
+    /// Elevator and rudder left, throttle and aileron right
+    Mode1,
+    /// Throttle and rudder left, elevator and aileron right
+    Mode2,
+    /// Elevator and aileron left, throttle and rudder right
+    Mode3,
+    /// Throttle and aileron left, elevator and rudder right
+    Mode4,
+}
+
+impl StickMode {
+    /// Physical axis of each logical axis, in AETR order
+    fn physical(self) -> [usize; STICK_AXES] {
+        match self {
+            Self::Mode1 => [RIGHT_X, LEFT_Y, RIGHT_Y, LEFT_X],
+            Self::Mode2 => [RIGHT_X, RIGHT_Y, LEFT_Y, LEFT_X],
+            Self::Mode3 => [LEFT_X, LEFT_Y, RIGHT_Y, RIGHT_X],
+            Self::Mode4 => [LEFT_X, RIGHT_Y, LEFT_Y, RIGHT_X],
+        }
+    }
+
+    /// Reorder the gimbal axes at the start of `axes` to AETR
+    pub fn apply<T: Copy>(self, axes: &mut [T]) {
+        if axes.len() < STICK_AXES {
+            return;
+        }
+        let physical = [axes[0], axes[1], axes[2], axes[3]];
+        for (axis, i) in axes.iter_mut().zip(self.physical().iter()) {
+            *axis = physical[*i];
+        }
+    }
+}
+
+/// Channel sent at each of the first positions, a permutation of `0..STICK_AXES`
+///
+/// E.g. `[2, 0, 1, 3]` sends channels in the TAER order of the AETR mixer outputs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChannelOrder(pub [u8; STICK_AXES]);
+
+impl ChannelOrder {
+    pub const AETR: Self = Self([0, 1, 2, 3]);
+
+    /// Reorder the first channels, the others stay in place
+    pub fn apply<T: Copy>(self, channels: &mut [T]) {
+        if channels.len() < STICK_AXES || !self.is_valid() {
+            return;
+        }
+        let mixed = [channels[0], channels[1], channels[2], channels[3]];
+        for (channel, i) in channels.iter_mut().zip(self.0.iter()) {
+            *channel = mixed[*i as usize];
+        }
+    }
+
+    /// Whether every channel is sent exactly once
+    pub fn is_valid(self) -> bool {
+        (0..STICK_AXES as u8).all(|i| self.0.contains(&i))
+    }
+}
+
+impl Default for ChannelOrder {
+    fn default() -> Self {
+        Self::AETR
+    }
+}
+
+/// Channel order of every output, receivers differ in what they expect
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ChannelOrders {
+    pub hid: ChannelOrder,
+    pub crsf: ChannelOrder,
+}
@@ -0,0 +1,266 @@
+//! Persistent settings in flash
+//!
+//! Settings are appended as records to a reserved flash sector, the last valid record wins. The
+//! sector is only erased once it is full. Each record is laid out as
+//! `[MAGIC: u16, length: u16, payload, crc32(payload): u32]`, all little endian, and starts at a
+//! multiple of four bytes.
+#[cfg(target_os = "none")]
+use stm32f4xx_hal::{flash::FlashExt, pac::FLASH};
+
+/// Size of the settings sector
+pub const SECTOR_SIZE: usize = 0x2_0000;
+
+/// Erasable and programmable memory
+pub trait Flash {
+    type Error;
+
+    /// Read the whole memory
+    fn read(&self) -> &[u8];
+
+    /// Erase the whole memory to `0xff`
+    fn erase(&mut self) -> Result<(), Self::Error>;
+
+    /// Program `data` at `offset`, the memory has to be erased there
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// The last sector of the STM32F401CC, it is excluded from `FLASH` in `memory.x`
+///
+/// Erasing takes up to a few seconds, during which the CPU stalls on every flash access.
+#[cfg(target_os = "none")]
+pub struct SettingsSector {
+    flash: FLASH,
+}
+
+#[cfg(target_os = "none")]
+impl SettingsSector {
+    const SECTOR: u8 = 5;
+    const OFFSET: usize = 0x2_0000;
+    pub const SIZE: usize = SECTOR_SIZE;
+
+    pub fn new(flash: FLASH) -> Self {
+        Self { flash }
+    }
+}
+
+#[cfg(target_os = "none")]
+impl Flash for SettingsSector {
+    type Error = stm32f4xx_hal::flash::Error;
+
+    fn read(&self) -> &[u8] {
+        &self.flash.read()[Self::OFFSET..Self::OFFSET + Self::SIZE]
+    }
+
+    fn erase(&mut self) -> Result<(), Self::Error> {
+        self.flash.unlocked().erase(Self::SECTOR)
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        self.flash
+            .unlocked()
+            .program(Self::OFFSET + offset, data.iter())
+    }
+}
+
+/// CRC-32 (IEEE 802.3) over the concatenation of `parts`
+pub fn crc32(parts: &[&[u8]]) -> u32 {
+    !parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(!0u32, |crc, byte| {
+            (0..8).fold(crc ^ *byte as u32, |crc, _| {
+                (crc >> 1) ^ (0xedb8_8320 & 0u32.wrapping_sub(crc & 1))
+            })
+        })
+}
+
+pub struct Storage<F> {
+    flash: F,
+    /// Payload of the last valid record
+    last: Option<(usize, usize)>,
+    /// Offset of the free space, `None` if the sector has to be erased before writing
+    end: Option<usize>,
+}
+
+const MAGIC: u16 = 0x5243;
+const HEADER: usize = 4;
+const TRAILER: usize = 4;
+
+impl<F: Flash> Storage<F> {
+    pub fn new(flash: F) -> Self {
+        let mut storage = Self {
+            flash,
+            last: None,
+            end: None,
+        };
+        storage.scan();
+        storage
+    }
+
+    /// Find the last valid record and the start of the free space
+    fn scan(&mut self) {
+        let data = self.flash.read();
+        let mut offset = 0;
+        self.last = None;
+        self.end = None;
+
+        while let Some(header) = data.get(offset..offset + HEADER) {
+            let magic = u16::from_le_bytes([header[0], header[1]]);
+            let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+
+            if header == [0xff; HEADER] {
+                // an interrupted write might have left data behind the missing header
+                if data[offset..].iter().all(|b| *b == 0xff) {
+                    self.end = Some(offset);
+                }
+                return;
+            }
+
+            let payload = offset + HEADER;
+            let crc = match data.get(payload + len..payload + len + TRAILER) {
+                Some(crc) if magic == MAGIC => u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]),
+                // garbage, do not write behind it
+                _ => return,
+            };
+
+            if crc32(&[&data[payload..payload + len]]) == crc {
+                self.last = Some((payload, len));
+            }
+
+            offset = Self::align(payload + len + TRAILER);
+        }
+    }
+
+    fn align(offset: usize) -> usize {
+        (offset + 3) & !3
+    }
+
+    /// Payload of the last valid record, if any
+    pub fn load(&self) -> Option<&[u8]> {
+        self.last
+            .map(|(offset, len)| &self.flash.read()[offset..offset + len])
+    }
+
+    /// Append a record with the concatenation of `parts` as payload, erasing the sector if it is
+    /// full
+    ///
+    /// The parts must not point into the flash, it might be erased before they are written.
+    pub fn save(&mut self, parts: &[&[u8]]) -> Result<(), F::Error> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        let size = HEADER + len + TRAILER;
+        let offset = match self.end {
+            Some(end) if end + size <= self.flash.read().len() => end,
+            _ => {
+                self.flash.erase()?;
+                0
+            }
+        };
+
+        let mut header = [0u8; HEADER];
+        header[..2].copy_from_slice(&MAGIC.to_le_bytes());
+        header[2..].copy_from_slice(&(len as u16).to_le_bytes());
+
+        let payload_offset = offset + HEADER;
+        let crc_offset = payload_offset + len;
+        let mut part_offset = payload_offset;
+        for part in parts {
+            self.flash.program(part_offset, part)?;
+            part_offset += part.len();
+        }
+        self.flash
+            .program(crc_offset, &crc32(parts).to_le_bytes())?;
+        // the header goes last, an interrupted write leaves garbage which is not trusted
+        self.flash.program(offset, &header)?;
+
+        self.last = Some((payload_offset, len));
+        self.end = Some(Self::align(crc_offset + TRAILER));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Flash in RAM, counting the erases
+    pub struct RamFlash {
+        pub data: std::vec::Vec<u8>,
+        pub erases: usize,
+    }
+
+    impl RamFlash {
+        pub fn new(size: usize) -> Self {
+            Self {
+                data: vec![0xff; size],
+                erases: 0,
+            }
+        }
+    }
+
+    impl Flash for RamFlash {
+        type Error = ();
+
+        fn read(&self) -> &[u8] {
+            &self.data
+        }
+
+        fn erase(&mut self) -> Result<(), ()> {
+            self.data.iter_mut().for_each(|b| *b = 0xff);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), ()> {
+            let memory = &mut self.data[offset..offset + data.len()];
+            assert!(memory.iter().all(|b| *b == 0xff), "not erased");
+            memory.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(&[b"123456789"]), 0xcbf4_3926);
+        assert_eq!(crc32(&[b"1234", b"", b"56789"]), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn last_record_wins() {
+        let mut storage = Storage::new(RamFlash::new(64));
+        assert_eq!(storage.load(), None);
+        storage.save(&[b"abc"]).unwrap();
+        storage.save(&[b"de", b"f"]).unwrap();
+        assert_eq!(storage.load(), Some(&b"def"[..]));
+        let storage = Storage::new(storage.flash);
+        assert_eq!(storage.load(), Some(&b"def"[..]));
+        assert_eq!(storage.end, Some(24));
+    }
+
+    #[test]
+    fn erased_when_full() {
+        let mut storage = Storage::new(RamFlash::new(32));
+        for record in [b"first...", b"second..", b"third..."].iter() {
+            storage.save(&[*record]).unwrap();
+        }
+        assert_eq!(storage.flash.erases, 1);
+        let storage = Storage::new(storage.flash);
+        assert_eq!(storage.load(), Some(&b"third..."[..]));
+    }
+
+    #[test]
+    fn damaged_records() {
+        let mut storage = Storage::new(RamFlash::new(64));
+        storage.save(&[b"good"]).unwrap();
+        storage.save(&[b"bad!"]).unwrap();
+        let mut flash = storage.flash;
+        flash.data[16] ^= 1;
+        // a write interrupted before the header leaves data behind the free space
+        flash.data[40] = 0;
+        let mut storage = Storage::new(flash);
+        assert_eq!(storage.load(), Some(&b"good"[..]));
+        assert_eq!(storage.end, None);
+        storage.save(&[b"new"]).unwrap();
+        assert_eq!(storage.flash.erases, 1);
+        assert_eq!(Storage::new(storage.flash).load(), Some(&b"new"[..]));
+    }
+}
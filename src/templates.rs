@@ -0,0 +1,221 @@
+//! Model templates
+//!
+//! Mixer setups of common layouts, the sticks are expected in the AETR order of the axes:
+//! aileron, elevator, throttle, rudder. Servo outputs are positive for a deflection down or to
+//! the right, reversed servos are left to the output limits.
+use crate::curves::Curve;
+use crate::globals::Param;
+use crate::mixer::{Condition, MixLine, Mixer, Source, Switch};
+use crate::models::Model;
+
+const AILERON: Source = Source::Axis(0);
+const ELEVATOR: Source = Source::Axis(1);
+const THROTTLE: Source = Source::Axis(2);
+const RUDDER: Source = Source::Axis(3);
+/// Flaps of the flaperons, the first dial
+const FLAPS: Source = Source::Axis(4);
+
+/// Down travel of differential ailerons, in percent less than the up travel
+const DIFFERENTIAL: i16 = 25;
+/// Switch for the crow braking of flaperons
+const CROW: Switch = Switch::Input(1);
+/// Deflection of the flaperons and elevator compensation of crow braking, in percent
+const CROW_AILERONS: i16 = -50;
+const CROW_ELEVATOR: i16 = 10;
+/// Arm and flight mode switches of a multirotor
+const ARM: Source = Source::Input(0);
+const MODE: Source = Source::Input(1);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Template {
+    /// Axes passed to the channels as they are
+    Passthrough,
+    /// Aileron, elevator, throttle, rudder and a second aileron on channel 4, with differential
+    Plane,
+    /// Flying wing, elevons on channels 0 and 1, throttle and rudder on 2 and 3
+    Elevons,
+    /// Aileron and throttle on channels 0 and 2, ruddervators on 1 and 3
+    VTail,
+    /// Plane with the ailerons on channels 0 and 4 doubling as flaps and crow brakes
+    Flaperons,
+    /// AETR, arm switch on channel 4 and flight mode switch on channel 5, startup check of the
+    /// throttle
+    Multirotor,
+}
+
+impl Template {
+    pub const ALL: [Self; 6] = [
+        Self::Passthrough,
+        Self::Plane,
+        Self::Elevons,
+        Self::VTail,
+        Self::Flaperons,
+        Self::Multirotor,
+    ];
+
+    /// New model of the layout
+    pub fn model(self) -> Model {
+        let mut model = Model::default();
+        if self == Self::Passthrough {
+            return model;
+        }
+
+        let line = |channel, source, weight: i16| MixLine {
+            weight: Param::Value(weight),
+            ..MixLine::new(channel, source)
+        };
+        let crow = |channel, weight| MixLine {
+            condition: Condition::On(CROW),
+            ..line(channel, Source::Max, weight)
+        };
+        let aetr = [
+            line(0, AILERON, 100),
+            line(1, ELEVATOR, 100),
+            line(2, THROTTLE, 100),
+            line(3, RUDDER, 100),
+        ];
+
+        let mut mixer = Mixer::default();
+        let mut add = |lines: &[MixLine]| {
+            for mix in lines.iter() {
+                mixer.add(*mix).ok();
+            }
+        };
+        match self {
+            Self::Passthrough => {}
+            Self::Plane => add(&[
+                MixLine {
+                    curve: Curve::Diff(Param::Value(-DIFFERENTIAL)),
+                    ..aetr[0]
+                },
+                aetr[1],
+                aetr[2],
+                aetr[3],
+                MixLine {
+                    curve: Curve::Diff(Param::Value(DIFFERENTIAL)),
+                    ..line(4, AILERON, -100)
+                },
+            ]),
+            Self::Elevons => add(&[
+                line(0, ELEVATOR, 100),
+                line(0, AILERON, 100),
+                line(1, ELEVATOR, 100),
+                line(1, AILERON, -100),
+                aetr[2],
+                aetr[3],
+            ]),
+            Self::VTail => add(&[
+                aetr[0],
+                line(1, ELEVATOR, 100),
+                line(1, RUDDER, 100),
+                aetr[2],
+                line(3, ELEVATOR, 100),
+                line(3, RUDDER, -100),
+            ]),
+            Self::Flaperons => add(&[
+                aetr[0],
+                line(0, FLAPS, 100),
+                crow(0, CROW_AILERONS),
+                aetr[1],
+                crow(1, CROW_ELEVATOR),
+                aetr[2],
+                aetr[3],
+                line(4, AILERON, -100),
+                line(4, FLAPS, 100),
+                crow(4, CROW_AILERONS),
+            ]),
+            Self::Multirotor => {
+                add(&[
+                    aetr[0],
+                    aetr[1],
+                    aetr[2],
+                    aetr[3],
+                    line(4, ARM, 100),
+                    line(5, MODE, 100),
+                ]);
+                model.throttle.channel = Some(2);
+                model.throttle.stick = THROTTLE;
+            }
+        }
+        model.mixer = mixer;
+        model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mixer::{Inputs, CHANNELS, MAX_MIXES};
+    use crate::slow::Follower;
+    use crate::types::{Value, VALUE_MAX};
+
+    const M: Value = VALUE_MAX;
+
+    /// Channels of a new model of `template`, the axes are in AETR order followed by the flaps
+    fn channels(template: Template, axes: [Value; 5], switches: [bool; 2]) -> [Value; CHANNELS] {
+        let inputs = Inputs {
+            axes: &axes,
+            trims: &[],
+            inputs: &switches,
+            logical: &[],
+            gestures: &[],
+            channels: &[],
+            flight_mode: 0,
+            globals: &[],
+            now: 0,
+            heli: &[],
+        };
+        let mut channels = [0; CHANNELS];
+        let followers = &mut [Follower::new(); MAX_MIXES];
+        template
+            .model()
+            .mixer
+            .evaluate(&inputs, followers, &mut channels);
+        channels
+    }
+
+    #[test]
+    fn passthrough() {
+        let channels = channels(Template::Passthrough, [1, 2, 3, 4, 5], [false; 2]);
+        assert_eq!(channels[..6], [1, 2, 3, 4, 5, 0]);
+    }
+
+    #[test]
+    fn plane() {
+        let right = channels(Template::Plane, [M, M / 2, -M, M / 4, 0], [false; 2]);
+        assert_eq!(right[..5], [M * 3 / 4, M / 2, -M, M / 4, -M]);
+        let left = channels(Template::Plane, [-M, 0, 0, 0, 0], [false; 2]);
+        assert_eq!(left[..5], [-M, 0, 0, 0, M * 3 / 4]);
+    }
+
+    #[test]
+    fn elevons() {
+        let channels = channels(Template::Elevons, [M / 2, M / 2, M, -M, 0], [false; 2]);
+        assert_eq!(channels[..4], [M, 0, M, -M]);
+    }
+
+    #[test]
+    fn v_tail() {
+        let channels = channels(Template::VTail, [M, M / 2, 0, M / 2, 0], [false; 2]);
+        assert_eq!(channels[..4], [M, M, 0, 0]);
+    }
+
+    #[test]
+    fn flaperons() {
+        let flaps = channels(Template::Flaperons, [M / 2, 0, 0, 0, M / 2], [false; 2]);
+        assert_eq!(flaps[..5], [M, 0, 0, 0, 0]);
+        let crow = channels(Template::Flaperons, [0; 5], [false, true]);
+        assert_eq!(crow[..5], [-M / 2, M / 10, 0, 0, -M / 2]);
+    }
+
+    #[test]
+    fn multirotor() {
+        let model = Template::Multirotor.model();
+        assert_eq!(model.throttle.channel, Some(2));
+        assert_eq!(model.throttle.stick, THROTTLE);
+        let disarmed = channels(Template::Multirotor, [1, 2, -M, 4, 0], [false, true]);
+        assert_eq!(disarmed[..6], [1, 2, -M, 4, -M, M]);
+        let armed = channels(Template::Multirotor, [0; 5], [true, false]);
+        assert_eq!(armed[4..6], [M, -M]);
+    }
+}
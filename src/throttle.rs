@@ -0,0 +1,121 @@
+//! Safety interlocks of the throttle channel
+//!
+//! The throttle channel is held at a safe value until the startup check passed, i.e. the throttle
+//! stick is low and the arming switch is off, and afterwards while the throttle is not armed or cut.
+use crate::mixer::{Inputs, Source, Switch, CHANNELS};
+use crate::types::{Value, VALUE_MAX};
+
+/// Interlocks of a model, all values in tenths of a percent
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ThrottleConfig {
+    /// Channel driving the motor, `None` disables the interlocks and the throttle always counts
+    /// as armed
+    pub channel: Option<u8>,
+    /// Throttle stick, it has to be low for the startup check and for arming
+    pub stick: Source,
+    /// Highest position of the stick counting as low
+    pub low: i16,
+    /// Output while the throttle is cut, not armed or the startup check is pending
+    pub safe: i16,
+    /// Switch cutting the throttle
+    pub cut: Option<Switch>,
+    /// Switch holding the throttle at `hold_value`, e.g. for autorotations of a heli
+    pub hold: Option<Switch>,
+    pub hold_value: i16,
+    /// Switch arming the throttle while the stick is low, always armed after the startup check
+    /// without one
+    pub arm: Option<Switch>,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            channel: None,
+            stick: Source::Axis(2),
+            low: -950,
+            safe: -1000,
+            cut: None,
+            hold: None,
+            hold_value: -1000,
+            arm: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum State {
+    /// Waiting for the stick and switches to be in their safe positions
+    Startup,
+    Disarmed,
+    Armed,
+}
+
+#[derive(Debug)]
+pub struct Throttle {
+    state: State,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Throttle {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Startup,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Whether the interlocked throttle channel is armed, i.e. the motor may run
+    pub fn motor_armed(&self, config: &ThrottleConfig) -> bool {
+        config.channel.is_some() && self.state == State::Armed
+    }
+
+    /// Start over with the startup check, e.g. for another model
+    pub fn reset(&mut self) {
+        self.state = State::Startup;
+    }
+
+    fn to_value(permille: i16) -> Value {
+        permille as Value * VALUE_MAX / 1000
+    }
+
+    /// Advance the state, returns the new state if it changed
+    pub fn update(&mut self, config: &ThrottleConfig, inputs: &Inputs) -> Option<State> {
+        let low = inputs.value(config.stick) <= Self::to_value(config.low);
+        let arm = config.arm.map(|switch| inputs.is_on(switch));
+
+        let state = match (self.state, arm) {
+            _ if config.channel.is_none() => State::Armed,
+            (State::Startup, Some(true)) => State::Startup,
+            (State::Startup, _) if !low => State::Startup,
+            (_, None) => State::Armed,
+            (_, Some(false)) => State::Disarmed,
+            (State::Disarmed, Some(true)) if !low => State::Disarmed,
+            (_, Some(true)) => State::Armed,
+        };
+        let changed = state != self.state;
+        self.state = state;
+        changed.then_some(state)
+    }
+
+    /// Replace the throttle channel of the limited outputs by its safe or hold value
+    pub fn apply(&self, config: &ThrottleConfig, inputs: &Inputs, outputs: &mut [Value; CHANNELS]) {
+        let output = match config.channel.and_then(|c| outputs.get_mut(c as usize)) {
+            Some(output) => output,
+            None => return,
+        };
+        let is_on = |switch: Option<Switch>| switch.is_some_and(|s| inputs.is_on(s));
+        if self.state != State::Armed || is_on(config.cut) {
+            *output = Self::to_value(config.safe);
+        } else if is_on(config.hold) {
+            *output = Self::to_value(config.hold_value);
+        }
+    }
+}
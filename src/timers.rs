@@ -0,0 +1,150 @@
+//! Flight timers
+//!
+//! Timers run while their trigger holds and either count up or count down from their start
+//! value, continuing into negative values once they passed zero. All times are in milliseconds
+//! of the monotonic clock, the values are whole seconds.
+use crate::mixer::{Condition, Inputs, Source};
+use crate::types::{Value, VALUE_MAX};
+
+/// Number of timers of a model
+pub const TIMERS: usize = 3;
+/// Seconds before the end of a countdown which are beeped
+const COUNTDOWN: i32 = 10;
+
+/// When a timer runs
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Trigger {
+    Off,
+    /// While the source is above the value in percent, e.g. the throttle channel
+    Above(Source, i16),
+    /// While the condition holds, e.g. a switch or a logical switch
+    Condition(Condition),
+    /// From the first time the condition holds until the timer is reset
+    Start(Condition),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimerConfig {
+    pub trigger: Trigger,
+    /// Start of the countdown in seconds, `0` counts up
+    pub start: u16,
+    /// Beep every full minute
+    pub minute_beep: bool,
+    /// Keep the elapsed time with the model, e.g. for the total run time
+    pub persistent: bool,
+}
+
+impl Default for TimerConfig {
+    fn default() -> Self {
+        Self {
+            trigger: Trigger::Off,
+            start: 0,
+            minute_beep: false,
+            persistent: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimerEvent {
+    /// A full minute passed
+    Minute,
+    /// One of the last seconds of a countdown passed
+    Countdown,
+    /// The countdown reached zero
+    End,
+    /// The timer stopped running
+    Stopped,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Timers {
+    pub config: [TimerConfig; TIMERS],
+    /// Elapsed milliseconds
+    elapsed: [u32; TIMERS],
+    running: [bool; TIMERS],
+    /// Whether a timer with [`Trigger::Start`] was started
+    started: [bool; TIMERS],
+}
+
+impl Timers {
+    /// Value in seconds, remaining time of a countdown and elapsed time otherwise
+    pub fn value(&self, index: usize) -> i32 {
+        let elapsed = (self.elapsed[index] / 1000) as i32;
+        match self.config[index].start {
+            0 => elapsed,
+            start => start as i32 - elapsed,
+        }
+    }
+
+    pub fn is_running(&self, index: usize) -> bool {
+        self.running[index]
+    }
+
+    pub fn reset(&mut self, index: usize) {
+        if let Some(elapsed) = self.elapsed.get_mut(index) {
+            *elapsed = 0;
+            self.started[index] = false;
+        }
+    }
+
+    /// Elapsed seconds, to be kept with the model
+    pub fn elapsed(&self) -> [u32; TIMERS] {
+        self.elapsed.map(|elapsed| elapsed / 1000)
+    }
+
+    /// Restore the elapsed seconds of the persistent timers
+    pub fn restore(&mut self, elapsed: [u32; TIMERS]) {
+        for ((timer, config), elapsed) in self
+            .elapsed
+            .iter_mut()
+            .zip(self.config.iter())
+            .zip(elapsed.iter())
+        {
+            *timer = if config.persistent {
+                elapsed.saturating_mul(1000)
+            } else {
+                0
+            };
+        }
+    }
+
+    /// Advance the running timers by `elapsed` milliseconds
+    pub fn update(&mut self, inputs: &Inputs, elapsed: u32) -> [Option<TimerEvent>; TIMERS] {
+        let mut events = [None; TIMERS];
+        for (i, event) in events.iter_mut().enumerate() {
+            let config = self.config[i];
+            let running = match config.trigger {
+                Trigger::Off => false,
+                Trigger::Above(source, x) => inputs.value(source) > x as Value * VALUE_MAX / 100,
+                Trigger::Condition(condition) => inputs.check(condition),
+                Trigger::Start(condition) => {
+                    self.started[i] |= inputs.check(condition);
+                    self.started[i]
+                }
+            };
+            if !running {
+                if self.running[i] {
+                    *event = Some(TimerEvent::Stopped);
+                }
+                self.running[i] = false;
+                continue;
+            }
+            self.running[i] = true;
+
+            let before = self.value(i);
+            self.elapsed[i] = self.elapsed[i].saturating_add(elapsed);
+            let value = self.value(i);
+            if value == before {
+                continue;
+            }
+            *event = match value {
+                0 if config.start != 0 => Some(TimerEvent::End),
+                v if config.start != 0 && v > 0 && v <= COUNTDOWN => Some(TimerEvent::Countdown),
+                v if config.minute_beep && v % 60 == 0 => Some(TimerEvent::Minute),
+                _ => None,
+            };
+        }
+        events
+    }
+}
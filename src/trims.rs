@@ -0,0 +1,113 @@
+//! Digital trims
+//!
+//! A trim switch is a rocker with the states of a [`ButtonState`]. Each step moves the trim of
+//! its axis by [`TrimConfig::step`], holding the switch repeats the steps with increasing speed.
+use crate::types::{ButtonState, VALUE_MAX};
+
+/// Trims of the stick axes
+pub const TRIMS: usize = 4;
+
+/// What a trim switch does
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TrimMode {
+    /// Offset the axis
+    Offset,
+    /// Pass the switch to the host as the HID buttons `up` and `down`
+    Buttons { up: usize, down: usize },
+    /// Adjust a global variable of the active flight mode by the step
+    Global(u8),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TrimConfig {
+    pub mode: TrimMode,
+    /// Digital inputs of the switch, as `(up, down)`
+    pub pins: (usize, usize),
+    /// Offset per step
+    pub step: i16,
+}
+
+/// Something worth a beep
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TrimEvent {
+    Center,
+    Limit,
+}
+
+/// Largest offset of a trim in either direction, a quarter of the travel
+pub const TRIM_LIMIT: i16 = (VALUE_MAX / 4) as i16;
+
+/// Apply `steps` to the trim `value`
+///
+/// The trim stops at the center, even if more steps are given.
+pub fn apply(value: &mut i16, steps: i32, config: &TrimConfig) -> Option<TrimEvent> {
+    if steps == 0 {
+        return None;
+    }
+
+    let old = *value as i32;
+    let new = old + steps * config.step as i32;
+
+    if (old < 0 && new >= 0) || (old > 0 && new <= 0) {
+        *value = 0;
+        Some(TrimEvent::Center)
+    } else if new.abs() >= TRIM_LIMIT as i32 {
+        *value = TRIM_LIMIT * new.signum() as i16;
+        (old.abs() < TRIM_LIMIT as i32).then_some(TrimEvent::Limit)
+    } else {
+        *value = new as i16;
+        None
+    }
+}
+
+/// Repeat and acceleration of a held trim switch
+#[derive(Debug, Default)]
+pub struct TrimSwitch {
+    /// Time of the next repeated step, if held
+    next: Option<u32>,
+    repeats: u16,
+}
+
+impl TrimSwitch {
+    /// Time before the first repeated step in milliseconds
+    const DELAY: u32 = 400;
+    /// Time between repeated steps in milliseconds
+    const INTERVAL: u32 = 100;
+    /// Repeats before the steps double in size, up to four-fold
+    const REPEATS_PER_SPEEDUP: u16 = 10;
+
+    /// Number of steps to take now, `now` is in milliseconds
+    pub fn update(&mut self, state: ButtonState, now: u32) -> i32 {
+        let direction = match state {
+            ButtonState::Up => 1,
+            ButtonState::Down => -1,
+            ButtonState::Neutral => {
+                self.next = None;
+                return 0;
+            }
+        };
+
+        match self.next {
+            // first press
+            None => {
+                self.next = Some(now.wrapping_add(Self::DELAY));
+                self.repeats = 0;
+                direction
+            }
+            Some(next) if (now.wrapping_sub(next) as i32) >= 0 => {
+                self.next = Some(now.wrapping_add(Self::INTERVAL));
+                self.repeats = self.repeats.saturating_add(1);
+                direction << (self.repeats / Self::REPEATS_PER_SPEEDUP).min(2)
+            }
+            Some(_) => 0,
+        }
+    }
+
+    /// Restart with the initial delay, e.g. once the center is reached
+    pub fn pause(&mut self, now: u32) {
+        if self.next.is_some() {
+            self.next = Some(now.wrapping_add(Self::DELAY));
+            self.repeats = 0;
+        }
+    }
+}
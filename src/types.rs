@@ -1,54 +1,78 @@
-#[allow(dead_code)]
-enum ButtonState {
+use crate::hid_map::HAT_CENTER;
+
+/// Fixed point value of an axis, mix or channel, `-VALUE_MAX..=VALUE_MAX` is the full travel
+pub type Value = i32;
+/// Finer than the ADC and the angle sensors, so their resolution is kept through the mixer
+pub const VALUE_MAX: Value = 8192;
+/// `VALUE_MAX` of settings records before version 14
+pub const OLD_VALUE_MAX: Value = 500;
+
+/// State of a three way momentary switch, e.g. a trim switch
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ButtonState {
     Up,
     Neutral,
     Down,
 }
 
-/// Channel count starts at 1
+impl ButtonState {
+    /// State from the inputs for the up and down position, both at once is invalid and neutral
+    pub fn from_inputs(up: bool, down: bool) -> Self {
+        match (up, down) {
+            (true, false) => Self::Up,
+            (false, true) => Self::Down,
+            _ => Self::Neutral,
+        }
+    }
+}
+
+/// Most axes of the HID report
+pub const REPORT_AXES: usize = 8;
+/// Most hat switches of the HID report
+pub const REPORT_HATS: usize = 4;
+
+/// State reported to the host, serialized by a [`Layout`](crate::report::Layout)
 ///
-/// Standard AETR mapping
-#[repr(packed)]
+/// The axes are the first channels, AETR unless the model sets another order for the host
 #[derive(Copy, Clone, Debug)]
 pub struct JoystickState {
-    /// Left Stick (Axis 0 and 1)
-    pub left_x: i16,
-    pub left_y: i16,
+    /// Sticks (axes 0 to 3) and dials, scaled to the range of the layout when serialized
+    pub axes: [Value; REPORT_AXES],
+
+    /// Buttons
+    pub buttons: u128,
+
+    /// Hat switches, `HAT_CENTER` without a direction
+    pub hats: [u8; REPORT_HATS],
 
-    /// Right Stick (Axis 2 and 3)
-    pub right_x: i16,
-    pub right_y: i16,
+    /// Status bits, see `STATUS_*`
+    pub status: u8,
+}
 
-    /// Dials
-    pub dial_1: i16,
-    pub dial_2: i16,
+/// The outputs are in failsafe
+pub const STATUS_FAILSAFE: u8 = 1 << 0;
+/// A special function asks the host to record the sticks
+pub const STATUS_RECORDING: u8 = 1 << 1;
 
-    /// Buttons
-    pub buttons: u8,
+impl Default for JoystickState {
+    fn default() -> Self {
+        Self::new([0; REPORT_AXES], 0, [HAT_CENTER; REPORT_HATS], 0)
+    }
 }
 
 impl JoystickState {
-    pub fn from_ppm_time() -> Self {
-        let buttons: u8 = 0;
-
+    /// Build a report from the axes, in the order of the report, and the button bits
+    pub fn new(
+        axes: [Value; REPORT_AXES],
+        buttons: u128,
+        hats: [u8; REPORT_HATS],
+        status: u8,
+    ) -> Self {
         JoystickState {
-            left_x: 0,
-            left_y: 0,
-            right_x: 0,
-            right_y: 0,
-            dial_1: 0,
-            dial_2: 0,
+            axes,
             buttons,
+            hats,
+            status,
         }
     }
-
-    // this is actually safe, as long as `JoystickState` is packed. More information:
-    // https://stackoverflow.com/questions/28127165/how-to-convert-struct-to-u8
-    /// Return a byte slice to this struct
-    pub unsafe fn as_u8_slice(&self) -> &[u8] {
-        ::core::slice::from_raw_parts(
-            (self as *const Self) as *const u8,
-            ::core::mem::size_of::<Self>(),
-        )
-    }
 }